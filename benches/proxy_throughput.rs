@@ -0,0 +1,105 @@
+//! Compares the `splice(2)` byte pump against the buffered copy it falls back to.
+//!
+//! Run with `cargo bench --bench proxy_throughput`.
+
+#[allow(dead_code)]
+#[path = "../src/client/pump.rs"]
+mod pump;
+
+use std::time::{Duration, Instant};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+static TOTAL_BYTES: usize = 1024 * 1024 * 1024;
+static CHUNK_SIZE: usize = 64 * 1024;
+static ROUNDS: u32 = 5;
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Buffered,
+    #[cfg(target_os = "linux")]
+    Splice,
+}
+
+#[tokio::main]
+async fn main() {
+    let modes = [
+        Mode::Buffered,
+        #[cfg(target_os = "linux")]
+        Mode::Splice,
+    ];
+
+    for mode in modes.iter().copied() {
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..ROUNDS {
+            elapsed += run(mode).await;
+        }
+
+        let average = elapsed / ROUNDS;
+        let throughput = TOTAL_BYTES as f64 / average.as_secs_f64() / (1024.0 * 1024.0);
+        println!(
+            "{:<8} {:>8.2?} per GiB, {:>8.1} MiB/s",
+            format!("{mode:?}"),
+            average,
+            throughput
+        );
+    }
+}
+
+/// Push `TOTAL_BYTES` through source -> pump -> sink over loopback.
+async fn run(mode: Mode) -> Duration {
+    let (mut source, pump_in) = socket_pair().await;
+    let (pump_out, mut sink) = socket_pair().await;
+
+    let start = Instant::now();
+
+    let writer = tokio::spawn(async move {
+        let chunk = vec![0x42; CHUNK_SIZE];
+        for _ in 0..TOTAL_BYTES / CHUNK_SIZE {
+            source.write_all(&chunk).await.unwrap();
+        }
+        source.shutdown().await.unwrap();
+    });
+
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            match sink.read(&mut buf).await.unwrap() {
+                0 => return total,
+                len => total += len,
+            }
+        }
+    });
+
+    let copied = match mode {
+        Mode::Buffered => pump::copy_buffered(&pump_in, &pump_out, None, |_| {}).await,
+        #[cfg(target_os = "linux")]
+        Mode::Splice => {
+            let pipe = pump::splice::Pipe::new().unwrap();
+            pump::splice::copy(&pump_in, &pump_out, None, pipe, |_| {}).await
+        }
+    }
+    .unwrap();
+    drop(pump_out);
+
+    let received = reader.await.unwrap();
+    let elapsed = start.elapsed();
+    writer.await.unwrap();
+
+    assert_eq!(copied as usize, TOTAL_BYTES);
+    assert_eq!(received, TOTAL_BYTES);
+
+    elapsed
+}
+
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+
+    (connected.unwrap(), accepted.unwrap().0)
+}
@@ -0,0 +1,296 @@
+//! Local HTTP/JSON API for managing the router while it runs, served when
+//! `adminbind` is set.
+//!
+//! Requests need `Authorization: Bearer <admintoken>` when `admintoken` is
+//! set, and it has to be to listen anywhere but loopback.
+
+use std::{net::SocketAddr, sync::Mutex};
+
+use axum::{
+    extract::{Path, Request},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use tokio::{net::TcpListener, task};
+use tracing::{error, info};
+
+use mc_router::{
+    config::{Action, Hostname, VirtualHost},
+    ConnectionInfo,
+};
+
+use crate::{reload, ROUTER};
+
+// set when the API is served anywhere but loopback, which it never is without a token
+static EXPOSED: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+#[derive(Debug, Serialize)]
+struct ApiError {
+    error: String,
+}
+
+fn api_error<E: ToString>(status: StatusCode, error: E) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: error.to_string(),
+        }),
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Maintenance {
+    message: Option<String>,
+}
+
+/// Whether the API can listen on `bind`, only loopback is allowed without a token.
+pub fn can_serve(bind: SocketAddr) -> bool {
+    bind.ip().is_loopback() || ROUTER.config().admin_token.is_some()
+}
+
+/// The address the API is served on when it isn't loopback, reloads can't
+/// remove `admintoken` while there is one.
+pub fn exposed_on() -> Option<SocketAddr> {
+    *EXPOSED.lock().unwrap()
+}
+
+/// Serve the API on `listener` until a newer router takes it over.
+pub async fn serve(listener: TcpListener, bind: SocketAddr) {
+    info!("Serving admin API on http://{}", bind);
+    if !bind.ip().is_loopback() {
+        *EXPOSED.lock().unwrap() = Some(bind);
+    }
+
+    let app = Router::new()
+        .route("/hosts", get(list_hosts))
+        .route(
+            "/hosts/:hostname",
+            get(get_host).put(put_host).delete(delete_host),
+        )
+        .route("/connections", get(list_connections))
+        .route("/connections/:id", delete(kick_connection))
+        .route(
+            "/maintenance",
+            get(get_maintenance)
+                .put(put_maintenance)
+                .delete(delete_maintenance),
+        )
+        .route("/reload", post(reload))
+        .layer(middleware::from_fn(authorize));
+
+    let handed_off = async { ROUTER.handed_off().await };
+    if let Err(err) = axum::serve(listener, app)
+        .with_graceful_shutdown(handed_off)
+        .await
+    {
+        error!(%err, "Admin API stopped");
+    }
+}
+
+/// Turn away requests without the configured token, if there is one, and
+/// every request when there isn't one but the API is served beyond loopback.
+async fn authorize(request: Request, next: Next) -> Response {
+    let token = ROUTER.config().admin_token.clone();
+    let token = match token {
+        Some(token) => token,
+        None if exposed_on().is_some() => {
+            return api_error(StatusCode::SERVICE_UNAVAILABLE, "admintoken isn't set")
+                .into_response();
+        }
+        None => return next.run(request).await,
+    };
+
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if !given.is_some_and(|given| same_token(given.as_bytes(), token.as_bytes())) {
+        return api_error(StatusCode::UNAUTHORIZED, "missing or wrong bearer token")
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+// looks at every byte whichever differ, so the time taken doesn't give the token away
+fn same_token(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn parse_hostname(hostname: &str) -> ApiResult<Hostname> {
+    hostname
+        .parse()
+        .map_err(|err| api_error(StatusCode::BAD_REQUEST, err))
+}
+
+/// Hosts from included files can only be changed by editing those files.
+fn included(hostname: &Hostname, file: &std::path::Path) -> (StatusCode, Json<ApiError>) {
+    api_error(
+        StatusCode::CONFLICT,
+        format!("{} is from {}", hostname, file.display()),
+    )
+}
+
+async fn save() -> ApiResult<()> {
+    task::spawn_blocking(crate::save_config)
+        .await
+        .map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, err))?
+        .map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, err))
+}
+
+/// Hosts are shown as written, `${...}` values aren't resolved so secrets stay secret.
+fn hosts_as_written() -> ApiResult<Vec<Value>> {
+    ROUTER
+        .config()
+        .hosts_as_written()
+        .map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, err))
+}
+
+fn host_as_written(hostname: &Hostname) -> ApiResult<Value> {
+    let index = ROUTER
+        .config()
+        .hosts
+        .get_index_of(hostname)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "no such host"))?;
+
+    hosts_as_written()?
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "no such host"))
+}
+
+async fn list_hosts() -> ApiResult<Json<Vec<Value>>> {
+    hosts_as_written().map(Json)
+}
+
+async fn get_host(Path(hostname): Path<String>) -> ApiResult<Json<Value>> {
+    let hostname = parse_hostname(&hostname)?;
+
+    host_as_written(&hostname).map(Json)
+}
+
+/// Add a host, or replace the action of an existing one.
+///
+/// An existing host's version rules and supported versions are kept.
+async fn put_host(
+    Path(hostname): Path<String>,
+    Json(action): Json<Action>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    let hostname = parse_hostname(&hostname)?;
+
+    let existed = ROUTER.update(|config| {
+        if let Some(file) = config.included_from(&hostname) {
+            return Err(included(&hostname, file));
+        }
+
+        let existing = config.hosts.get(&hostname);
+        let existed = existing.is_some();
+        let host = VirtualHost {
+            hostname: hostname.clone(),
+            action,
+            versions: existing
+                .map(|host| host.versions.clone())
+                .unwrap_or_default(),
+            supported: existing.and_then(|host| host.supported.clone()),
+        };
+        config.hosts.insert(hostname.clone(), host);
+        Ok(existed)
+    })?;
+    save().await?;
+
+    let host = Json(host_as_written(&hostname)?);
+    if existed {
+        info!(%hostname, "Host updated through admin API");
+        Ok((StatusCode::OK, host))
+    } else {
+        info!(%hostname, "Host added through admin API");
+        Ok((StatusCode::CREATED, host))
+    }
+}
+
+async fn delete_host(Path(hostname): Path<String>) -> ApiResult<StatusCode> {
+    let hostname = parse_hostname(&hostname)?;
+
+    ROUTER.update(|config| {
+        if let Some(file) = config.included_from(&hostname) {
+            return Err(included(&hostname, file));
+        }
+        if config.hosts.shift_remove(&hostname).is_none() {
+            return Err(api_error(StatusCode::NOT_FOUND, "no such host"));
+        }
+        if config.default_host() == Some(&hostname) {
+            config.set_default_host(None);
+        }
+        Ok(())
+    })?;
+    save().await?;
+
+    info!(%hostname, "Host removed through admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_connections() -> Json<Vec<ConnectionInfo>> {
+    Json(ROUTER.connections())
+}
+
+async fn kick_connection(Path(id): Path<u64>) -> ApiResult<StatusCode> {
+    if ROUTER.kick(id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(api_error(StatusCode::NOT_FOUND, "no such connection"))
+    }
+}
+
+async fn get_maintenance() -> Json<Maintenance> {
+    Json(Maintenance {
+        message: ROUTER.config().maintenance.clone(),
+    })
+}
+
+/// Start kicking new logins with the given message.
+async fn put_maintenance(Json(maintenance): Json<Maintenance>) -> ApiResult<Json<Maintenance>> {
+    #[allow(clippy::or_fun_call)]
+    let message = maintenance
+        .message
+        .unwrap_or("The server is down for maintenance".into());
+
+    ROUTER.update(|config| config.maintenance = Some(message.clone()));
+    save().await?;
+
+    info!("Maintenance enabled through admin API");
+    Ok(Json(Maintenance {
+        message: Some(message),
+    }))
+}
+
+async fn delete_maintenance() -> ApiResult<StatusCode> {
+    ROUTER.update(|config| config.maintenance = None);
+    save().await?;
+
+    info!("Maintenance disabled through admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reload() -> ApiResult<StatusCode> {
+    task::spawn_blocking(|| reload::reload(&ROUTER))
+        .await
+        .map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, err))?
+        .map_err(|err| api_error(StatusCode::BAD_REQUEST, err))?;
+
+    info!("Reloaded config through admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,87 +1,655 @@
-use crate::{config, CONFIG};
-use io::BufRead;
-use std::io;
+use crate::{reload, save_config, ROUTER};
+use mc_router::{
+    config::{
+        Action, ForwardAction, Hostname, LoginAction, ServerAddr, StaticAction, StatusAction,
+        VirtualHost,
+    },
+    ConnectionInfo,
+};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    validate::Validator, Context, Editor, Helper,
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, str::FromStr};
+
+static HISTORY_PATH: &str = ".mc_router_history";
+static PROMPT: &str = "router> ";
+// commands that change the running config without saving it
+static EDITS: &[&str] = &["add", "remove", "status", "login", "default"];
+
+static COMMANDS: &[(&str, &str)] = &[
+    ("list", "list - list virtual hosts"),
+    ("add", "add <hostname> [target] - add a host, forwarding to target or showing a static status"),
+    ("remove", "remove <hostname> - remove a host"),
+    ("status", "status <hostname> forward <target> | static [description] - set a host's status action"),
+    ("login", "login <hostname> forward <target> | kick [message] - set a host's login action"),
+    ("default", "default <hostname> | none - set the host used when no other matches"),
+    ("save", "save - write changes to the config file"),
+    ("reload", "reload - read the config file again, discarding unsaved changes"),
+    ("connections", "connections [host=<hostname>] [user=<username>] [backend=<address>] - list open connections"),
+    ("kick", "kick <id> | kick host <hostname> - close connections"),
+    ("stop", "stop - stop the router, again to cut proxied sessions"),
+    ("help", "help - show this list"),
+];
+
+/// What a command printed and whether it succeeded, written to stdout or sent
+/// back to `mc_router ctl`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Output {
+    pub ok: bool,
+    pub lines: Vec<String>,
+    /// Machine readable version of what was listed, if anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl Output {
+    fn new() -> Self {
+        Output {
+            ok: true,
+            lines: Vec::new(),
+            data: None,
+        }
+    }
+
+    fn print<S: Into<String>>(&mut self, line: S) {
+        self.lines.push(line.into());
+    }
+
+    fn fail<S: Into<String>>(&mut self, line: S) {
+        self.ok = false;
+        self.print(line);
+    }
+
+    fn data<T: Serialize>(&mut self, data: &T) {
+        self.data = serde_json::to_value(data).ok();
+    }
+}
 
-// TODO: re add commands to modify config through cli (also overhaul the entire thing, maybe add colors)
 pub fn start() {
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let line = line.unwrap();
-        let mut parts = line.split_whitespace();
+    let mut editor = match Editor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            tracing::error!(%err, "Failed to start cli");
+            return;
+        }
+    };
+    editor.set_helper(Some(CliHelper));
+    // there won't be one the first time
+    let _ = editor.load_history(HISTORY_PATH);
 
-        let command = if let Some(command) = parts.next() {
-            command.to_lowercase()
-        } else {
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            // raw mode swallows the signal, treat it the same
+            Err(ReadlineError::Interrupted) => {
+                ROUTER.shutdown();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return,
+            Err(err) => {
+                tracing::error!(%err, "Failed to read command");
+                return;
+            }
+        };
+
+        if line.trim().is_empty() {
             continue;
+        }
+
+        let _ = editor.add_history_entry(line.as_str());
+        let _ = editor.save_history(HISTORY_PATH);
+
+        for line in execute(&line).lines {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Run a single command line.
+pub fn execute(line: &str) -> Output {
+    let mut out = Output::new();
+    let mut parts = line.split_whitespace();
+
+    let command = if let Some(command) = parts.next() {
+        command.to_lowercase()
+    } else {
+        out.fail("No command given, try 'help'");
+        return out;
+    };
+
+    match command.as_str() {
+        "list" => execute_list(&command, &mut parts, &mut out),
+        "add" => execute_add(&command, &mut parts, &mut out),
+        "remove" => execute_remove(&command, &mut parts, &mut out),
+        "status" => execute_status(&command, &mut parts, &mut out),
+        "login" => execute_login(&command, &mut parts, &mut out),
+        "default" => execute_default(&command, &mut parts, &mut out),
+        "save" => execute_save(&command, &mut parts, &mut out),
+        "reload" => execute_reload(&command, &mut parts, &mut out),
+        "connections" => execute_connections(&command, &mut parts, &mut out),
+        "kick" => execute_kick(&command, &mut parts, &mut out),
+        "stop" => execute_stop(&command, &mut parts, &mut out),
+        "help" => execute_help(&command, &mut parts, &mut out),
+
+        _ => out.fail(format!("Unknown command '{}', try 'help'", command)),
+    }
+
+    if out.ok && EDITS.contains(&command.as_str()) {
+        unsaved(&mut out);
+    }
+
+    out
+}
+
+/// Completes command names, then hostnames and action kinds for the commands that take them.
+struct CliHelper;
+
+impl Completer for CliHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let word = &line[start..];
+        let previous = line[..start].split_whitespace().collect::<Vec<_>>();
+
+        let candidates: Vec<String> = match previous.as_slice() {
+            [] => COMMANDS.iter().map(|(name, _)| name.to_string()).collect(),
+            ["remove"] | ["status"] | ["login"] | ["default"] | ["kick", "host"] => {
+                let config = ROUTER.config();
+                config.hosts.keys().map(Hostname::to_string).collect()
+            }
+            ["status", _] => vec!["forward".into(), "static".into()],
+            ["login", _] => vec!["forward".into(), "kick".into()],
+            ["kick"] => vec!["host".into()],
+            _ => Vec::new(),
+        };
+
+        let mut candidates = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .collect::<Vec<_>>();
+        candidates.sort();
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CliHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CliHelper {}
+
+impl Validator for CliHelper {}
+
+impl Helper for CliHelper {}
+
+fn usage(command: &str, out: &mut Output) {
+    if let Some((_, usage)) = COMMANDS.iter().find(|(name, _)| *name == command) {
+        out.fail(format!("usage: {}", usage));
+    }
+}
+
+/// Parse the next argument, printing why it's invalid if it is.
+fn parse_arg<'i, T, A>(command: &str, args: &mut A, out: &mut Output) -> Option<T>
+where
+    T: FromStr<Err = String>,
+    A: Iterator<Item = &'i str>,
+{
+    match args.next().map(str::parse) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(err)) => {
+            out.fail(err);
+            None
+        }
+        None => {
+            usage(command, out);
+            None
+        }
+    }
+}
+
+fn rest<'i, A: Iterator<Item = &'i str>>(args: &mut A) -> Option<String> {
+    let rest = args.collect::<Vec<_>>().join(" ");
+    if rest.is_empty() {
+        None
+    } else {
+        Some(rest)
+    }
+}
+
+fn unsaved(out: &mut Output) {
+    out.print("  (not saved yet, run 'save' to keep it)");
+}
+
+fn describe_action(action: &Action) -> String {
+    match action {
+        Action::Forward {
+            forward: ForwardAction(target),
+        } => format!("> {}", target),
+        action => format!(
+            "status {}, login {}",
+            describe_status(&action.get_status_action()),
+            describe_login(&action.get_login_action())
+        ),
+    }
+}
+
+fn describe_status(action: &StatusAction) -> String {
+    match action {
+        StatusAction::Forward {
+            forward: ForwardAction(target),
+        } => format!("> {}", target),
+        StatusAction::Static { r#static } => format!(
+            "# {:?} {}/{}",
+            r#static
+                .description
+                .as_deref()
+                .unwrap_or("A Minecraft Server"),
+            r#static.cur_players.unwrap_or(0),
+            r#static.max_players.unwrap_or(20)
+        ),
+        StatusAction::Custom { custom } => format!("custom {}", custom),
+        StatusAction::Script { script } => format!("script {}", script.display()),
+    }
+}
+
+fn describe_login(action: &LoginAction) -> String {
+    match action {
+        LoginAction::Forward {
+            forward: ForwardAction(target),
+        } => format!("> {}", target),
+        LoginAction::Static { r#static } => format!(
+            "kick {:?}",
+            r#static.kick_message.as_deref().unwrap_or("Disconnected")
+        ),
+        LoginAction::Custom { custom } => format!("custom {}", custom),
+        LoginAction::Script { script } => format!("script {}", script.display()),
+    }
+}
+
+fn execute_list<'i, A: Iterator<Item = &'i str>>(
+    _command: &str,
+    _args: &'i mut A,
+    out: &mut Output,
+) {
+    let config = ROUTER.config();
+    let default_host = config.get_default_host().map(|host| &host.hostname);
+
+    let mut hosts = config.hosts.values().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+
+    out.print("virtual hosts:");
+    for host in &hosts {
+        let marker = if Some(&host.hostname) == default_host {
+            "*"
+        } else {
+            " "
         };
 
-        match command.as_str() {
-            // "list" => execute_list(&command, &mut parts),
-            // "forward" => execute_forward(&command, &mut parts),
-            "reload" => execute_reload(&command, &mut parts),
-
-            _ => println!("Unknown command '{}'", command),
-        }
-    }
-}
-
-// fn execute_list<'i, A: Iterator<Item = &'i str>>(_command: &str, _args: &'i mut A) {
-//     let config = CONFIG.read().unwrap();
-
-//     println!("virtual hosts:");
-//     for vhost in config.virtualhosts.iter() {
-//         print!("  {} ", vhost.hostname);
-//         match &vhost.target {
-//             HostTarget::Forward(target) => println!("> {}", target),
-//             HostTarget::Status {
-//                 online_players,
-//                 max_players,
-//                 description,
-//             } => println!("# {:?} - {}/{}", description, online_players, max_players),
-//         }
-//     }
-// }
-
-// fn execute_forward<'i, A: Iterator<Item = &'i str>>(_command: &str, args: &'i mut A) {
-//     let hostname = args.next().map(|s| s.parse());
-//     let target = args.next().map(|s| s.parse());
-
-//     if let (Some(hostname), Some(target)) = (hostname, target) {
-//         if hostname.is_err() {
-//             println!("hostname is invalid");
-//             return;
-//         }
-
-//         if target.is_err() {
-//             println!("target is invalid");
-//             return;
-//         }
-
-//         {
-//             let mut config = CONFIG.write().unwrap();
-//             (*config).forwards.push(Forward {
-//                 hostname: hostname.unwrap(),
-//                 target: target.unwrap(),
-//             });
-//         }
-
-//         config::save(&CONFIG.read().unwrap()).unwrap();
-//         println!("new forward created");
-//     } else {
-//         println!("usage: forward <hostname> <target>");
-//     }
-// }
-
-fn execute_reload<'i, A: Iterator<Item = &'i str>>(_command: &str, _args: &'i mut A) {
-    let config = config::load();
-
-    match config {
-        Ok(config) => {
-            *CONFIG.write().unwrap() = config;
-            println!("> Reloaded config");
+        match &host.action {
+            Action::Forward {
+                forward: ForwardAction(target),
+            } => out.print(format!(" {} {} > {}", marker, host.hostname, target)),
+            action => {
+                out.print(format!(" {} {}", marker, host.hostname));
+                out.print(format!(
+                    "      status {}",
+                    describe_status(&action.get_status_action())
+                ));
+                out.print(format!(
+                    "      login  {}",
+                    describe_login(&action.get_login_action())
+                ));
+            }
+        }
+        if let Some(supported) = &host.supported {
+            out.print(format!("      supports {}", supported.releases()));
+        }
+        for rule in &host.versions {
+            out.print(format!(
+                "      {} {}",
+                rule.range,
+                describe_action(&rule.action)
+            ));
+        }
+    }
+
+    out.data(&hosts);
+}
+
+fn execute_add<'i, A: Iterator<Item = &'i str>>(command: &str, args: &'i mut A, out: &mut Output) {
+    let hostname: Hostname = match parse_arg(command, args, out) {
+        Some(hostname) => hostname,
+        None => return,
+    };
+
+    let action = match args.next().map(str::parse::<ServerAddr>) {
+        Some(Ok(target)) => Action::Forward {
+            forward: ForwardAction(target),
+        },
+        Some(Err(err)) => return out.fail(err),
+        None => Action::Static {
+            r#static: StaticAction::default(),
+        },
+    };
+
+    let added = ROUTER.update(|config| {
+        if config.hosts.contains_key(&hostname) {
+            return false;
+        }
+
+        config.hosts.insert(
+            hostname.clone(),
+            VirtualHost {
+                hostname: hostname.clone(),
+                action,
+                versions: Vec::new(),
+                supported: None,
+            },
+        );
+        true
+    });
+    if !added {
+        return out.fail(format!("{} already exists", hostname));
+    }
+
+    out.print(format!("> Added {}", hostname));
+}
+
+fn execute_remove<'i, A: Iterator<Item = &'i str>>(
+    command: &str,
+    args: &'i mut A,
+    out: &mut Output,
+) {
+    let hostname: Hostname = match parse_arg(command, args, out) {
+        Some(hostname) => hostname,
+        None => return,
+    };
+
+    let removed = ROUTER.update(|config| {
+        if let Some(file) = config.included_from(&hostname) {
+            return Err(included(&hostname, file));
+        }
+        if config.hosts.shift_remove(&hostname).is_none() {
+            return Err(format!("No host {}", hostname));
+        }
+
+        let was_default = config.default_host() == Some(&hostname);
+        if was_default {
+            config.set_default_host(None);
+        }
+        Ok(was_default)
+    });
+
+    match removed {
+        Ok(was_default) => {
+            out.print(format!("> Removed {}", hostname));
+            if was_default {
+                out.print("  it was the default host, there is no default now");
+            }
+        }
+        Err(err) => out.fail(err),
+    }
+}
+
+fn execute_status<'i, A: Iterator<Item = &'i str>>(
+    command: &str,
+    args: &'i mut A,
+    out: &mut Output,
+) {
+    let hostname: Hostname = match parse_arg(command, args, out) {
+        Some(hostname) => hostname,
+        None => return,
+    };
+
+    let status = match args.next() {
+        Some("forward") => match parse_arg(command, args, out) {
+            Some(target) => StatusAction::Forward {
+                forward: ForwardAction(target),
+            },
+            None => return,
+        },
+        Some("static") => StatusAction::Static {
+            r#static: StaticAction {
+                description: rest(args),
+                ..Default::default()
+            },
+        },
+        _ => return usage(command, out),
+    };
+
+    edit_host(&hostname, out, |action| Action::Conditional {
+        status,
+        login: action.get_login_action(),
+    });
+}
+
+fn execute_login<'i, A: Iterator<Item = &'i str>>(
+    command: &str,
+    args: &'i mut A,
+    out: &mut Output,
+) {
+    let hostname: Hostname = match parse_arg(command, args, out) {
+        Some(hostname) => hostname,
+        None => return,
+    };
+
+    let login = match args.next() {
+        Some("forward") => match parse_arg(command, args, out) {
+            Some(target) => LoginAction::Forward {
+                forward: ForwardAction(target),
+            },
+            None => return,
+        },
+        Some("kick") => LoginAction::Static {
+            r#static: StaticAction {
+                kick_message: rest(args),
+                ..Default::default()
+            },
+        },
+        _ => return usage(command, out),
+    };
+
+    edit_host(&hostname, out, |action| Action::Conditional {
+        status: action.get_status_action(),
+        login,
+    });
+}
+
+fn included(hostname: &Hostname, file: &Path) -> String {
+    format!(
+        "{} is from {}, edit that file instead",
+        hostname,
+        file.display()
+    )
+}
+
+fn edit_host<F: FnOnce(&Action) -> Action>(hostname: &Hostname, out: &mut Output, edit: F) {
+    let edited = ROUTER.update(|config| {
+        if let Some(file) = config.included_from(hostname) {
+            return Err(included(hostname, file));
         }
+
+        match config.hosts.get_mut(hostname) {
+            Some(host) => {
+                host.action = edit(&host.action);
+                Ok(())
+            }
+            None => Err(format!("No host {}, add it first", hostname)),
+        }
+    });
+
+    match edited {
+        Ok(()) => out.print(format!("> Updated {}", hostname)),
+        Err(err) => out.fail(err),
+    }
+}
+
+fn execute_default<'i, A: Iterator<Item = &'i str>>(
+    command: &str,
+    args: &'i mut A,
+    out: &mut Output,
+) {
+    let hostname = match args.next() {
+        Some("none") => {
+            ROUTER.update(|config| config.set_default_host(None));
+            return out.print("> Removed the default host");
+        }
+        Some(hostname) => match hostname.parse::<Hostname>() {
+            Ok(hostname) => hostname,
+            Err(err) => return out.fail(err),
+        },
+        None => return usage(command, out),
+    };
+
+    let set = ROUTER.update(|config| {
+        let exists = config.hosts.contains_key(&hostname);
+        if exists {
+            config.set_default_host(Some(hostname.clone()));
+        }
+        exists
+    });
+    if !set {
+        return out.fail(format!("No host {}, add it first", hostname));
+    }
+
+    out.print(format!("> Set the default host to {}", hostname));
+}
+
+fn execute_save<'i, A: Iterator<Item = &'i str>>(
+    _command: &str,
+    _args: &'i mut A,
+    out: &mut Output,
+) {
+    match save_config() {
+        Ok(()) => out.print("> Saved config"),
+        Err(error) => out.fail(format!("Failed to save config:\n    {}", error)),
+    }
+}
+
+fn execute_reload<'i, A: Iterator<Item = &'i str>>(
+    _command: &str,
+    _args: &'i mut A,
+    out: &mut Output,
+) {
+    match reload::reload(&ROUTER) {
+        Ok(()) => out.print("> Reloaded config"),
         Err(error) => {
-            println!("Failed to read config:\n    {}", error);
+            out.fail(format!("Failed to read config:\n    {}", error));
         }
     }
 }
+
+fn execute_connections<'i, A: Iterator<Item = &'i str>>(
+    command: &str,
+    args: &'i mut A,
+    out: &mut Output,
+) {
+    let mut connections = ROUTER.connections();
+
+    for filter in args {
+        let (key, value) = match filter.split_once('=') {
+            Some(filter) => filter,
+            None => return usage(command, out),
+        };
+
+        let matches: fn(&ConnectionInfo, &str) -> bool = match key {
+            "host" => {
+                |connection, value| connection.host.as_ref().is_some_and(|host| *host == value)
+            }
+            "user" => |connection, value| {
+                connection
+                    .username
+                    .as_ref()
+                    .is_some_and(|username| username.eq_ignore_ascii_case(value))
+            },
+            "backend" => |connection, value| {
+                connection
+                    .backend
+                    .as_ref()
+                    .is_some_and(|backend| backend.to_string() == value)
+            },
+            other => {
+                return out.fail(format!(
+                    "Unknown filter '{}', expected host, user or backend",
+                    other
+                ));
+            }
+        };
+
+        connections.retain(|connection| matches(connection, value));
+    }
+
+    out.print(format!("{} connections:", connections.len()));
+    for connection in &connections {
+        let username = connection.username.as_deref().unwrap_or("-");
+        let host = connection
+            .host
+            .as_ref()
+            .map_or("-".to_owned(), Hostname::to_string);
+        let backend = connection
+            .backend
+            .as_ref()
+            .map_or("-".to_owned(), ToString::to_string);
+
+        out.print(format!(
+            "  #{} {} {} {} > {} ({}s, {}B sent, {}B received)",
+            connection.id,
+            connection.addr,
+            username,
+            host,
+            backend,
+            connection.duration_secs,
+            connection.bytes_sent,
+            connection.bytes_received
+        ));
+    }
+
+    out.data(&connections);
+}
+
+fn execute_kick<'i, A: Iterator<Item = &'i str>>(command: &str, args: &'i mut A, out: &mut Output) {
+    match (args.next(), args.next()) {
+        (Some("host"), Some(hostname)) => match hostname.parse::<Hostname>() {
+            Ok(hostname) => {
+                let kicked = ROUTER.kick_host(&hostname);
+                out.print(format!("> Kicked {} connections from {}", kicked, hostname));
+            }
+            Err(err) => out.fail(err),
+        },
+        (Some(id), None) => match id.trim_start_matches('#').parse() {
+            Ok(id) if ROUTER.kick(id) => out.print(format!("> Kicked connection #{}", id)),
+            Ok(id) => out.fail(format!("No connection #{}", id)),
+            Err(_) => usage(command, out),
+        },
+        _ => usage(command, out),
+    }
+}
+
+fn execute_stop<'i, A: Iterator<Item = &'i str>>(
+    _command: &str,
+    _args: &'i mut A,
+    out: &mut Output,
+) {
+    ROUTER.shutdown();
+    out.print("> Stopping router");
+}
+
+fn execute_help<'i, A: Iterator<Item = &'i str>>(
+    _command: &str,
+    _args: &'i mut A,
+    out: &mut Output,
+) {
+    for (_, usage) in COMMANDS {
+        out.print(format!("  {}", usage));
+    }
+}
@@ -0,0 +1,281 @@
+// TODO: this should probably be merged into mcproto at some point
+
+use std::{future::Future, io, time::Duration};
+
+use bytes::{Buf, BufMut, BytesMut};
+use color_eyre::{eyre::eyre, Result};
+use mcproto::{packet::Packet, types::BufType};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Instant},
+};
+
+// largest length a 3 byte varint can hold, same limit as vanilla
+static MAX_PACKET_LENGTH: usize = 2_097_151;
+
+/// An uncompressed, unencrypted packet stream, which is all the router needs
+/// before handing the connection off to a backend.
+#[derive(Debug)]
+pub struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+    /// Every read and write has to be done by then.
+    deadline: Option<Instant>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, deadline: Option<Instant>) -> Self {
+        Connection {
+            stream,
+            buffer: BytesMut::new(),
+            deadline,
+        }
+    }
+
+    pub async fn expect_next_packet<P: Packet + BufType>(&mut self) -> Result<P> {
+        let (packet_id, mut body) = self.read_frame().await?;
+
+        if packet_id != P::PACKET_ID {
+            return Err(eyre!(
+                "unexpected packet id {:#04x}, expected {:#04x}",
+                packet_id,
+                P::PACKET_ID
+            ));
+        }
+
+        let (packet, _) = P::buf_read_len(&mut body)?;
+        Ok(packet)
+    }
+
+    pub async fn write_packet<P: Packet + BufType>(&mut self, packet: P) -> Result<()> {
+        let mut body = BytesMut::new();
+        packet.buf_write(&mut body);
+
+        self.write_frame(P::PACKET_ID, &body).await
+    }
+
+    /// Write a packet from its id and already encoded body.
+    pub async fn write_frame(&mut self, packet_id: i32, body: &[u8]) -> Result<()> {
+        let mut packet = BytesMut::with_capacity(body.len() + 5);
+        write_varint(&mut packet, packet_id);
+        packet.put_slice(body);
+
+        let mut frame = BytesMut::with_capacity(packet.len() + 5);
+        write_varint(&mut frame, packet.len() as i32);
+        frame.put(packet);
+
+        until(self.deadline, self.stream.write_all(&frame)).await?;
+        Ok(())
+    }
+
+    /// Any bytes that were read past the last packet, along with the underlying stream.
+    pub fn into_bytes_stream(self) -> (BytesMut, TcpStream) {
+        (self.buffer, self.stream)
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+
+    /// Read the next packet's id and body without decoding it.
+    pub async fn read_frame(&mut self) -> Result<(i32, BytesMut)> {
+        loop {
+            if let Some(frame) = split_frame(&mut self.buffer)? {
+                return Ok(frame);
+            }
+
+            let len = until(self.deadline, self.stream.read_buf(&mut self.buffer)).await?;
+            if len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+pub async fn with_timeout<T, F>(timeout: Option<Duration>, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match timeout {
+        Some(timeout) => time::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => future.await,
+    }
+}
+
+/// Like [`with_timeout`], for a deadline shared by several reads and writes so
+/// a client can't stretch them out by sending a byte at a time.
+pub async fn until<T, F>(deadline: Option<Instant>, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => future.await,
+    }
+}
+
+/// Split a whole packet off the front of the buffer, returning its id and body.
+fn split_frame(buffer: &mut BytesMut) -> Result<Option<(i32, BytesMut)>> {
+    let (length, length_len) = match read_varint(&buffer[..])? {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+
+    let length = length as usize;
+    if length > MAX_PACKET_LENGTH {
+        return Err(eyre!("packet length {} is too long", length));
+    }
+
+    if buffer.len() < length_len + length {
+        return Ok(None);
+    }
+
+    buffer.advance(length_len);
+    let mut body = buffer.split_to(length);
+
+    let (packet_id, packet_id_len) =
+        read_varint(&body[..])?.ok_or_else(|| eyre!("packet is missing an id"))?;
+    body.advance(packet_id_len);
+
+    Ok(Some((packet_id, body)))
+}
+
+/// Returns `None` when the buffer ends before the varint does.
+pub fn read_varint(buf: &[u8]) -> Result<Option<(i32, usize)>> {
+    let mut value = 0;
+
+    for (index, byte) in buf.iter().enumerate() {
+        if index == 5 {
+            return Err(eyre!("varint is too long"));
+        }
+
+        value |= ((byte & 0x7f) as i32) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, index + 1)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn write_varint<B: BufMut>(buf: &mut B, value: i32) {
+    let mut value = value as u32;
+
+    loop {
+        if value & !0x7f == 0 {
+            buf.put_u8(value as u8);
+            return;
+        }
+
+        buf.put_u8((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(value: i32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, value);
+        buf
+    }
+
+    fn frame(packet_id: i32, body: &[u8]) -> BytesMut {
+        let mut packet = varint(packet_id);
+        packet.extend_from_slice(body);
+
+        let mut frame = BytesMut::from(&varint(packet.len() as i32)[..]);
+        frame.put_slice(&packet);
+        frame
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let values = [
+            (0, 1),
+            (1, 1),
+            (127, 1),
+            (128, 2),
+            (255, 2),
+            (25565, 3),
+            (2_097_151, 3),
+            (2_097_152, 4),
+            (i32::MAX, 5),
+            (-1, 5),
+            (i32::MIN, 5),
+        ];
+
+        for (value, len) in values {
+            let buf = varint(value);
+            assert_eq!(buf.len(), len, "length of {}", value);
+            assert_eq!(read_varint(&buf).unwrap(), Some((value, len)));
+        }
+    }
+
+    #[test]
+    fn varint_known_encodings() {
+        assert_eq!(varint(-1), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(varint(i32::MIN), [0x80, 0x80, 0x80, 0x80, 0x08]);
+        assert_eq!(varint(25565), [0xdd, 0xc7, 0x01]);
+    }
+
+    #[test]
+    fn varint_incomplete() {
+        assert_eq!(read_varint(&[]).unwrap(), None);
+        assert_eq!(read_varint(&[0x80, 0x80]).unwrap(), None);
+    }
+
+    #[test]
+    fn varint_too_long() {
+        assert!(read_varint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).is_err());
+    }
+
+    #[test]
+    fn frames_split_off_whole_packets() {
+        let mut buffer = frame(0x00, b"hello");
+        buffer.put(frame(0x01, &[1, 2, 3]));
+
+        let (packet_id, body) = split_frame(&mut buffer).unwrap().unwrap();
+        assert_eq!((packet_id, &body[..]), (0x00, &b"hello"[..]));
+        let (packet_id, body) = split_frame(&mut buffer).unwrap().unwrap();
+        assert_eq!((packet_id, &body[..]), (0x01, &[1, 2, 3][..]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn partial_frame_waits_for_more() {
+        let whole = frame(0x00, b"hello");
+        let mut buffer = BytesMut::from(&whole[..4]);
+
+        assert!(split_frame(&mut buffer).unwrap().is_none());
+        assert_eq!(buffer.len(), 4);
+
+        buffer.put_slice(&whole[4..]);
+        assert!(split_frame(&mut buffer).unwrap().is_some());
+    }
+
+    #[test]
+    fn oversized_length_is_refused() {
+        let mut buffer = BytesMut::from(&varint(MAX_PACKET_LENGTH as i32 + 1)[..]);
+        assert!(split_frame(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn negative_length_is_refused() {
+        let mut buffer = BytesMut::from(&varint(-1)[..]);
+        assert!(split_frame(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn empty_packet_is_refused() {
+        let mut buffer = BytesMut::from(&varint(0)[..]);
+        assert!(split_frame(&mut buffer).is_err());
+    }
+}
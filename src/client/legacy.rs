@@ -1,10 +1,6 @@
 // TODO: this should probably be merged into mcproto at some point
 
-use std::{
-    convert::TryInto,
-    io::{Read as _, Write},
-    net::TcpStream,
-};
+use std::{convert::TryInto, io};
 
 use bytes::{Buf, BufMut, BytesMut};
 use color_eyre::Result;
@@ -12,21 +8,50 @@ use mcproto::{
     packet_derive::BufType,
     types::{BufType, ReadError},
 };
-use tracing::{trace, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
+};
+use tracing::{info, trace, warn};
+
+use super::{connection::until, ratelimit::Kind, Client};
 
-pub fn maybe_handle_legacy_status(stream: &mut TcpStream) -> Result<bool> {
+pub async fn maybe_handle_legacy_status(
+    stream: &mut TcpStream,
+    deadline: Option<Instant>,
+    client: &Client,
+) -> Result<bool> {
     let mut buf = vec![0; 3];
-    let len = stream.peek(&mut buf)?;
-    assert!(len >= 1, "todo: test how peek works with eof");
+    let len = until(deadline, stream.peek(&mut buf)).await?;
+    // closed before sending anything, reading the handshake will see it too
+    if len == 0 {
+        return Ok(false);
+    }
+
+    // a modern handshake 254 bytes or longer starts with 0xfe too, only what
+    // each legacy ping starts with is taken as one
+    if !matches!(&buf[..len], [0xfe] | [0xfe, 0x01] | [0xfe, 0x01, 0xfa]) {
+        return Ok(false);
+    }
+
+    // legacy pings are always status requests
+    let throttle = client.permit.check(Kind::Status);
+    let motd = match throttle {
+        Some(throttle) => {
+            info!(?throttle, "Throttling legacy ping");
+            throttle.message(&client.router.config.read().unwrap().rate_limit)
+        }
+        None => "A Minecraft Server".to_owned(),
+    };
 
     if len == 1 && &buf[0..1] == b"\xfe" {
-        let motd = "A Minecraft Server";
         let online_players = 0;
         let max_players = 13;
 
         let status_line = format!("{}§{}§{}", motd, online_players, max_players);
 
-        write_legacy_kick_packet(stream, status_line)?;
+        write_legacy_kick_packet(stream, deadline, status_line).await?;
 
         return Ok(true);
     }
@@ -34,7 +59,6 @@ pub fn maybe_handle_legacy_status(stream: &mut TcpStream) -> Result<bool> {
     if len == 2 && &buf[0..2] == b"\xfe\x01" {
         let protocol_version = 47;
         let minecraft_version = "1.4.2";
-        let motd = "A Minecraft Server";
         let online_players = 0;
         let max_players = 14;
 
@@ -43,19 +67,22 @@ pub fn maybe_handle_legacy_status(stream: &mut TcpStream) -> Result<bool> {
             protocol_version, minecraft_version, motd, online_players, max_players
         );
 
-        write_legacy_kick_packet(stream, status_line)?;
+        write_legacy_kick_packet(stream, deadline, status_line).await?;
         return Ok(true);
     }
 
     if len >= 3 && &buf[0..3] == b"\xfe\x01\xfa" {
         // skip peeked bytes
-        stream.read_exact(&mut buf)?;
+        until(deadline, stream.read_exact(&mut buf)).await?;
 
         let mut buffer = BytesMut::new();
 
         let ping_request = loop {
             let mut read_buffer = [0; 64];
-            let len = stream.read(&mut read_buffer)?;
+            let len = until(deadline, stream.read(&mut read_buffer)).await?;
+            if len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
             buffer.put(&read_buffer[..len]);
 
             match Legacy16PingRequest::buf_read_len(&mut buffer.clone()) {
@@ -87,7 +114,6 @@ pub fn maybe_handle_legacy_status(stream: &mut TcpStream) -> Result<bool> {
 
         let protocol_version = 73;
         let minecraft_version = "1.6.1";
-        let motd = "A Minecraft Server";
         let online_players = 0;
         let max_players = 16;
 
@@ -96,7 +122,7 @@ pub fn maybe_handle_legacy_status(stream: &mut TcpStream) -> Result<bool> {
             protocol_version, minecraft_version, motd, online_players, max_players
         );
 
-        write_legacy_kick_packet(stream, status_line)?;
+        write_legacy_kick_packet(stream, deadline, status_line).await?;
 
         return Ok(true);
     }
@@ -154,7 +180,11 @@ pub mod utf16_be_string {
     // }
 }
 
-fn write_legacy_kick_packet<S: AsRef<str>>(stream: &mut TcpStream, status_line: S) -> Result<()> {
+async fn write_legacy_kick_packet<S: AsRef<str>>(
+    stream: &mut TcpStream,
+    deadline: Option<Instant>,
+    status_line: S,
+) -> Result<()> {
     let status_line = status_line.as_ref();
 
     // note this is code units (`.encode_utf16()`) rather than characters (`.chars()`)
@@ -171,7 +201,7 @@ fn write_legacy_kick_packet<S: AsRef<str>>(stream: &mut TcpStream, status_line:
     status_buf.extend((status_line_len as u16).to_be_bytes()); // status line length
     status_buf.extend(status_line.encode_utf16().flat_map(|c| c.to_be_bytes())); // status line
 
-    stream.write_all(&status_buf)?;
+    until(deadline, stream.write_all(&status_buf)).await?;
 
     Ok(())
 }
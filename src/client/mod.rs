@@ -1,146 +1,283 @@
 use std::{
     io,
-    net::{Shutdown, SocketAddr, TcpStream},
-    thread,
+    net::{Shutdown, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use mcproto::{
-    self, handshake, role,
-    stdio::{self, StdIoConnection},
-};
+use connection::{with_timeout, Connection};
+use mcproto::{self, handshake};
 use multi_version::Protocol;
-use tracing::{debug, error, field, info, info_span, trace, warn};
+use ratelimit::ConnectionPermit;
+use registry::Registration;
+use socket2::SockRef;
+use tokio::{
+    net::{self, TcpStream},
+    time::Instant,
+};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument};
 
 use crate::{
-    config::{Action, ForwardAction, Hostname, LoginAction, StatusAction},
-    CONFIG,
+    config::{
+        Action, ForwardAction, Hostname, LoginAction, ProtocolRange, ServerAddr, StaticAction,
+        StatusAction,
+    },
+    custom::{self, Route},
+    metrics,
+    router::Inner,
+    shutdown,
 };
 
+#[cfg(feature = "scripting")]
+use crate::scripting;
+
+mod connection;
 mod legacy;
 mod multi_version;
+pub mod ping;
+mod pump;
+pub mod ratelimit;
+pub mod registry;
 mod version_impls;
 
-pub fn spawn_client_handler(stream: TcpStream, addr: SocketAddr) {
-    thread::Builder::new()
-        .name(format!("client({addr})"))
-        .spawn(move || {
-            let span = info_span!("client", %addr, username = field::Empty);
-            let _enter = span.enter();
+pub use multi_version::LoginStart;
+
+// hosts handed off to by custom actions and scripts can use one too, up to
+// this many times before it's taken to be a loop
+static MAX_HAND_OFFS: usize = 8;
+
+/// Everything known about a client before it sends a handshake.
+#[derive(Debug)]
+pub struct Client {
+    pub addr: SocketAddr,
+    /// Bind address of the listener that accepted the connection.
+    pub listener: SocketAddr,
+    pub permit: ConnectionPermit,
+    pub tracked: Registration,
+    pub router: Arc<Inner>,
+}
 
-            match handshake_client(stream, addr) {
+pub fn spawn_client_handler(stream: TcpStream, client: Client) {
+    let span = info_span!("client", addr = %client.addr, username = field::Empty);
+    let tracked = client.tracked.handle();
+
+    tokio::spawn(
+        async move {
+            // dropping the handler closes both the client and backend sockets
+            let result = tokio::select! {
+                result = handshake_client(stream, client) => result,
+                _ = tracked.kicked() => {
+                    info!("Connection kicked");
+                    return;
+                }
+            };
+
+            match result {
                 Ok(_) => {
                     info!("Connection closed");
                 }
-                Err(err) => match err.downcast_ref::<mcproto::error::Error>() {
-                    Some(mcproto::error::Error::StreamShutdown) => {
+                Err(err) => match err.downcast_ref::<io::Error>() {
+                    Some(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
                         info!("Connection closed");
                     }
-                    Some(mcproto::error::Error::UnexpectedDisconect(err)) => {
+                    Some(err) if is_disconnect(err) => {
                         info!("Connection closed: {}", err.kind());
                     }
+                    Some(err) if err.kind() == io::ErrorKind::TimedOut => {
+                        info!("Connection timed out");
+                    }
                     _other => {
                         error!(%err, "Error while handling connection");
                     }
                 },
             }
-        })
-        .unwrap();
+        }
+        .instrument(span),
+    );
 }
 
-fn handshake_client(mut stream: TcpStream, addr: SocketAddr) -> color_eyre::Result<()> {
+async fn handshake_client(mut stream: TcpStream, client: Client) -> color_eyre::Result<()> {
     debug!("Accepted connection");
 
-    if legacy::maybe_handle_legacy_status(&mut stream)? {
+    // one deadline for everything until the connection is being proxied, so
+    // trickling in bytes doesn't keep pushing it back
+    let handshake_timeout = client.router.config.read().unwrap().timeouts.handshake();
+    let deadline = Some(Instant::now() + handshake_timeout);
+
+    if legacy::maybe_handle_legacy_status(&mut stream, deadline, &client).await? {
+        metrics::LEGACY_PINGS.inc();
         return Ok(());
     }
 
-    let mut sioc = stdio::accept_stdio_stream::<role::Server, handshake::HandshakingState>(stream)?;
+    let mut sioc = Connection::new(stream, deadline);
 
-    let handshake: handshake::Handshake = sioc.expect_next_packet()?;
+    let handshake: handshake::Handshake = sioc.expect_next_packet().await?;
     trace!(?handshake, "Recieved handshake packet");
     info!("New client has connected");
 
     match handshake.protocol_version {
-        3 => handle_client::<version_impls::ProtocolV3>(sioc, handshake, addr),
-        4 => handle_client::<version_impls::ProtocolV4>(sioc, handshake, addr),
-        5 => handle_client::<version_impls::ProtocolV5>(sioc, handshake, addr),
-        47 => handle_client::<version_impls::ProtocolV47>(sioc, handshake, addr),
-        107 => handle_client::<version_impls::ProtocolV107>(sioc, handshake, addr),
-        108 => handle_client::<version_impls::ProtocolV108>(sioc, handshake, addr),
-        109 => handle_client::<version_impls::ProtocolV109>(sioc, handshake, addr),
-        110 => handle_client::<version_impls::ProtocolV110>(sioc, handshake, addr),
-        210 => handle_client::<version_impls::ProtocolV210>(sioc, handshake, addr),
-        315 => handle_client::<version_impls::ProtocolV315>(sioc, handshake, addr),
-        316 => handle_client::<version_impls::ProtocolV316>(sioc, handshake, addr),
-        335 => handle_client::<version_impls::ProtocolV335>(sioc, handshake, addr),
-        338 => handle_client::<version_impls::ProtocolV338>(sioc, handshake, addr),
-        340 => handle_client::<version_impls::ProtocolV340>(sioc, handshake, addr),
-        393 => handle_client::<version_impls::ProtocolV393>(sioc, handshake, addr),
-        401 => handle_client::<version_impls::ProtocolV401>(sioc, handshake, addr),
-        404 => handle_client::<version_impls::ProtocolV404>(sioc, handshake, addr),
-        477 => handle_client::<version_impls::ProtocolV477>(sioc, handshake, addr),
-        480 => handle_client::<version_impls::ProtocolV480>(sioc, handshake, addr),
-        485 => handle_client::<version_impls::ProtocolV485>(sioc, handshake, addr),
-        490 => handle_client::<version_impls::ProtocolV490>(sioc, handshake, addr),
-        498 => handle_client::<version_impls::ProtocolV498>(sioc, handshake, addr),
-        573 => handle_client::<version_impls::ProtocolV573>(sioc, handshake, addr),
-        575 => handle_client::<version_impls::ProtocolV575>(sioc, handshake, addr),
-        578 => handle_client::<version_impls::ProtocolV578>(sioc, handshake, addr),
-        735 => handle_client::<version_impls::ProtocolV735>(sioc, handshake, addr),
-        736 => handle_client::<version_impls::ProtocolV736>(sioc, handshake, addr),
-        751 => handle_client::<version_impls::ProtocolV751>(sioc, handshake, addr),
-        753 => handle_client::<version_impls::ProtocolV753>(sioc, handshake, addr),
-        754 => handle_client::<version_impls::ProtocolV754>(sioc, handshake, addr),
-        755 => handle_client::<version_impls::ProtocolV755>(sioc, handshake, addr),
-        756 => handle_client::<version_impls::ProtocolV756>(sioc, handshake, addr),
-        757 => handle_client::<version_impls::ProtocolV757>(sioc, handshake, addr),
-        758 => handle_client::<version_impls::ProtocolV758>(sioc, handshake, addr),
-        759 => handle_client::<version_impls::ProtocolV759>(sioc, handshake, addr),
-        760 => handle_client::<version_impls::ProtocolV760>(sioc, handshake, addr),
-        761 => handle_client::<version_impls::ProtocolV761>(sioc, handshake, addr),
-        762 => handle_client::<version_impls::ProtocolV762>(sioc, handshake, addr),
-        763 => handle_client::<version_impls::ProtocolV763>(sioc, handshake, addr),
-        764 => handle_client::<version_impls::ProtocolV764>(sioc, handshake, addr),
-        765 => handle_client::<version_impls::ProtocolV765>(sioc, handshake, addr),
-        766 => handle_client::<version_impls::ProtocolV766>(sioc, handshake, addr),
-        767 => handle_client::<version_impls::ProtocolV767>(sioc, handshake, addr),
+        3 => handle_client::<version_impls::ProtocolV3>(sioc, handshake, &client).await,
+        4 => handle_client::<version_impls::ProtocolV4>(sioc, handshake, &client).await,
+        5 => handle_client::<version_impls::ProtocolV5>(sioc, handshake, &client).await,
+        47 => handle_client::<version_impls::ProtocolV47>(sioc, handshake, &client).await,
+        107 => handle_client::<version_impls::ProtocolV107>(sioc, handshake, &client).await,
+        108 => handle_client::<version_impls::ProtocolV108>(sioc, handshake, &client).await,
+        109 => handle_client::<version_impls::ProtocolV109>(sioc, handshake, &client).await,
+        110 => handle_client::<version_impls::ProtocolV110>(sioc, handshake, &client).await,
+        210 => handle_client::<version_impls::ProtocolV210>(sioc, handshake, &client).await,
+        315 => handle_client::<version_impls::ProtocolV315>(sioc, handshake, &client).await,
+        316 => handle_client::<version_impls::ProtocolV316>(sioc, handshake, &client).await,
+        335 => handle_client::<version_impls::ProtocolV335>(sioc, handshake, &client).await,
+        338 => handle_client::<version_impls::ProtocolV338>(sioc, handshake, &client).await,
+        340 => handle_client::<version_impls::ProtocolV340>(sioc, handshake, &client).await,
+        393 => handle_client::<version_impls::ProtocolV393>(sioc, handshake, &client).await,
+        401 => handle_client::<version_impls::ProtocolV401>(sioc, handshake, &client).await,
+        404 => handle_client::<version_impls::ProtocolV404>(sioc, handshake, &client).await,
+        477 => handle_client::<version_impls::ProtocolV477>(sioc, handshake, &client).await,
+        480 => handle_client::<version_impls::ProtocolV480>(sioc, handshake, &client).await,
+        485 => handle_client::<version_impls::ProtocolV485>(sioc, handshake, &client).await,
+        490 => handle_client::<version_impls::ProtocolV490>(sioc, handshake, &client).await,
+        498 => handle_client::<version_impls::ProtocolV498>(sioc, handshake, &client).await,
+        573 => handle_client::<version_impls::ProtocolV573>(sioc, handshake, &client).await,
+        575 => handle_client::<version_impls::ProtocolV575>(sioc, handshake, &client).await,
+        578 => handle_client::<version_impls::ProtocolV578>(sioc, handshake, &client).await,
+        735 => handle_client::<version_impls::ProtocolV735>(sioc, handshake, &client).await,
+        736 => handle_client::<version_impls::ProtocolV736>(sioc, handshake, &client).await,
+        751 => handle_client::<version_impls::ProtocolV751>(sioc, handshake, &client).await,
+        753 => handle_client::<version_impls::ProtocolV753>(sioc, handshake, &client).await,
+        754 => handle_client::<version_impls::ProtocolV754>(sioc, handshake, &client).await,
+        755 => handle_client::<version_impls::ProtocolV755>(sioc, handshake, &client).await,
+        756 => handle_client::<version_impls::ProtocolV756>(sioc, handshake, &client).await,
+        757 => handle_client::<version_impls::ProtocolV757>(sioc, handshake, &client).await,
+        758 => handle_client::<version_impls::ProtocolV758>(sioc, handshake, &client).await,
+        759 => handle_client::<version_impls::ProtocolV759>(sioc, handshake, &client).await,
+        760 => handle_client::<version_impls::ProtocolV760>(sioc, handshake, &client).await,
+        761 => handle_client::<version_impls::ProtocolV761>(sioc, handshake, &client).await,
+        762 => handle_client::<version_impls::ProtocolV762>(sioc, handshake, &client).await,
+        763 => handle_client::<version_impls::ProtocolV763>(sioc, handshake, &client).await,
+        764 => handle_client::<version_impls::ProtocolV764>(sioc, handshake, &client).await,
+        765 => handle_client::<version_impls::ProtocolV765>(sioc, handshake, &client).await,
+        766 => handle_client::<version_impls::ProtocolV766>(sioc, handshake, &client).await,
+        767 => handle_client::<version_impls::ProtocolV767>(sioc, handshake, &client).await,
 
         other => {
             warn!("unknown protocol version: {}, defaulting to latest.", other);
 
-            handle_client::<version_impls::ProtocolV767>(sioc, handshake, addr)
+            handle_client::<version_impls::ProtocolV767>(sioc, handshake, &client).await
         }
     }
 }
 
-fn handle_client<P: Protocol>(
-    connection: StdIoConnection<role::Server, handshake::HandshakingState>,
+async fn handle_client<P: Protocol>(
+    mut connection: Connection,
     handshake: handshake::Handshake,
-    addr: SocketAddr,
-) -> color_eyre::Result<()>
-where
-    <P::StatusState as mcproto::state::RoleStatePackets<mcproto::role::Server>>::RecvPacket:
-        mcproto::packet::PacketFromIdBody,
-    <P::LoginState as mcproto::state::RoleStatePackets<mcproto::role::Server>>::RecvPacket:
-        mcproto::packet::PacketFromIdBody,
-{
-    debug!("Finding action for {}", handshake.server_address);
-    let action = match find_action(&handshake.server_address.parse().unwrap()) {
-        Some(action) => action,
-        None => {
-            info!("No action found for {}", handshake.server_address);
-            connection.shutdown(Shutdown::Both)?;
+    client: &Client,
+) -> color_eyre::Result<()> {
+    // unknown versions are handled as the latest, don't give each its own series
+    let protocol_version = if handshake.protocol_version == P::VERSION {
+        P::VERSION.to_string()
+    } else {
+        "other".to_owned()
+    };
+    // transfers and anything else are closed below, they get a series once they're handled
+    let next_state = match handshake.next_state {
+        handshake::NextState::Status => Some("status"),
+        handshake::NextState::Login => Some("login"),
+        _ => None,
+    };
+    if let Some(next_state) = next_state {
+        metrics::HANDSHAKES
+            .with_label_values(&[&protocol_version, next_state])
+            .inc();
+    }
+
+    if let Some(throttle) = client.permit.check((&handshake.next_state).into()) {
+        info!(?throttle, "Throttling connection");
+        let message = throttle.message(&client.router.config.read().unwrap().rate_limit);
+        let status = multi_version::StatusResponse {
+            version_name: "router".into(),
+            protocol_version: handshake.protocol_version,
+            max_players: 0,
+            online_players: 0,
+            description: message.clone(),
+        };
+        return refuse::<P>(connection, &handshake, status, message).await;
+    }
+
+    // scanners send these all the time, they're not worth an error
+    let hostname: Hostname = match handshake.server_address.parse() {
+        Ok(hostname) => hostname,
+        Err(err) => {
+            info!(
+                "Invalid hostname {:?} in handshake, closing connection: {}",
+                handshake.server_address, err
+            );
+            connection.shutdown().await?;
             return Ok(());
         }
     };
-    debug!(hostname = %handshake.server_address, ?action, "Found action");
+    client
+        .tracked
+        .set_handshake(handshake.protocol_version, hostname.clone());
+
+    debug!("Finding action for {}", handshake.server_address);
+    let found = find_action(
+        &client.router,
+        &client.listener,
+        &hostname,
+        handshake.protocol_version,
+    );
+    let script = client.router.config.read().unwrap().script.clone();
+    // a top level script can still route connections no host matched
+    if found.is_none() && script.is_none() {
+        info!("No action found for {}", handshake.server_address);
+        connection.shutdown().await?;
+        return Ok(());
+    }
+    debug!(hostname = %handshake.server_address, ?found, "Found action");
+    let mut host = found.as_ref().map(|(host, _)| host.clone());
+
+    if let Some(supported) = unsupported(
+        &client.router,
+        &client.listener,
+        &hostname,
+        handshake.protocol_version,
+    ) {
+        info!(
+            "Protocol {} isn't supported by {}, refusing connection",
+            handshake.protocol_version, supported
+        );
+        let message = unsupported_message(&supported);
+        let status = multi_version::StatusResponse {
+            version_name: supported.releases(),
+            protocol_version: supported.nearest(handshake.protocol_version),
+            max_players: 0,
+            online_players: 0,
+            description: message.clone(),
+        };
+        return refuse::<P>(connection, &handshake, status, message).await;
+    }
+    if let Some(host) = &host {
+        client.tracked.set_host(host);
+    }
 
     match handshake.next_state {
         handshake::NextState::Status => {
-            let mut connection = P::status_state(connection);
             debug!("State changed to status");
 
-            match action.get_status_action() {
+            let request = custom_request(client, &handshake, &hostname, host.as_ref(), None);
+            let chosen = choose_action(&client.router, request, &mut host, found, script).await;
+            track_host(client, host.as_ref(), "status");
+            let status = match chosen {
+                Some(action) => action.get_status_action(),
+                None => {
+                    connection.shutdown().await?;
+                    return Ok(());
+                }
+            };
+
+            match status {
                 StatusAction::Static { r#static } => {
                     #[allow(clippy::or_fun_call)]
                     let version_name = r#static.version_name.unwrap_or("router".into());
@@ -152,7 +289,7 @@ where
                     #[allow(clippy::or_fun_call)]
                     let description = r#static.description.unwrap_or("A Minecraft Server".into());
 
-                    let request = P::read_status_request(&mut connection)?;
+                    let request = P::read_status_request(&mut connection).await?;
                     trace!(?request, "Recieved request packet");
 
                     info!("Sending status");
@@ -165,37 +302,79 @@ where
                             online_players,
                             description: description.clone(),
                         },
-                    )?;
+                    )
+                    .await?;
 
                     // attempt ping/pong
-                    let ping = P::read_ping_request(&mut connection)?;
+                    let ping = P::read_ping_request(&mut connection).await?;
                     trace!(?ping, "Recieved ping packet");
                     P::write_ping_response(
                         &mut connection,
                         multi_version::PingResponse {
                             payload: ping.payload,
                         },
-                    )?;
+                    )
+                    .await?;
 
                     trace!("Closing connection");
-                    connection.shutdown(Shutdown::Both)?;
+                    connection.shutdown().await?;
                 }
                 StatusAction::Forward {
                     forward: ForwardAction(target),
                 } => {
                     info!("Forwarding status to {target}");
-                    P::forward_status(connection, addr, handshake, target)?;
-                } // StatusAction::Modify { modify: _ } => todo!(),
+                    P::forward_status(connection, client, handshake, target).await?;
+                }
+                // decided on already
+                StatusAction::Custom { .. } | StatusAction::Script { .. } => unreachable!(),
+                // StatusAction::Modify { modify: _ } => todo!(),
             }
         }
         handshake::NextState::Login => {
-            let mut connection = P::login_state(connection);
             debug!("State changed to login");
-            let login_start = P::read_login_start(&mut connection)?;
+            let login_start = P::read_login_start(&mut connection).await?;
             tracing::Span::current().record("username", &login_start.username);
+            client.tracked.set_username(&login_start.username);
             trace!(?login_start, "Recieved login start packet");
 
-            match action.get_login_action() {
+            let kick_message = {
+                let config = client.router.config.read().unwrap();
+                let maintenance = config.maintenance.clone();
+                client.router.shutdown.kick_message(&config).or(maintenance)
+            };
+            if let Some(kick_message) = kick_message {
+                track_host(client, host.as_ref(), "login");
+                info!("Router isn't accepting logins, sending disconnect");
+                P::write_disconnect(
+                    &mut connection,
+                    multi_version::Disconnect {
+                        reason: kick_message,
+                    },
+                )
+                .await?;
+
+                connection.shutdown().await?;
+                return Ok(());
+            }
+
+            let request = custom_request(
+                client,
+                &handshake,
+                &hostname,
+                host.as_ref(),
+                Some(&login_start),
+            );
+            let chosen = choose_action(&client.router, request, &mut host, found, script).await;
+            track_host(client, host.as_ref(), "login");
+            let login = match chosen {
+                Some(action) => action.get_login_action(),
+                None => {
+                    connection.shutdown().await?;
+                    return Ok(());
+                }
+            };
+
+            match login {
                 LoginAction::Static { r#static } => {
                     #[allow(clippy::or_fun_call)]
                     let kick_message = r#static.kick_message.unwrap_or("Disconnected".into());
@@ -206,79 +385,457 @@ where
                         multi_version::Disconnect {
                             reason: kick_message,
                         },
-                    )?;
+                    )
+                    .await?;
 
                     trace!("Closing connection");
-                    connection.shutdown(Shutdown::Both)?;
+                    connection.shutdown().await?;
                 }
                 LoginAction::Forward {
                     forward: ForwardAction(target),
                 } => {
                     info!("forwarding login to {target}");
-                    P::forward_login(connection, addr, handshake, login_start, target)?;
+                    P::forward_login(connection, client, handshake, login_start, target).await?;
                 }
+                // decided on already
+                LoginAction::Custom { .. } | LoginAction::Script { .. } => unreachable!(),
             }
         }
 
         handshake::NextState::Transfer => {
-            todo!("transfer state");
+            info!("Transfers aren't supported, closing connection");
+            connection.shutdown().await?;
         }
 
         handshake::NextState::Unknown(other) => {
-            unreachable!("unknown next state: {}", other)
+            info!("Unknown next state {}, closing connection", other);
+            connection.shutdown().await?;
         }
     }
 
     Ok(())
 }
 
-fn find_action(hostname: &Hostname) -> Option<Action> {
-    let config = CONFIG.read().unwrap();
+/// Answer a connection without routing it, status requests are sent `status`
+/// and logins are kicked with `reason`.
+async fn refuse<P: Protocol>(
+    mut connection: Connection,
+    handshake: &handshake::Handshake,
+    status: multi_version::StatusResponse,
+    reason: String,
+) -> color_eyre::Result<()> {
+    match handshake.next_state {
+        handshake::NextState::Status => {
+            let request = P::read_status_request(&mut connection).await?;
+            trace!(?request, "Recieved request packet");
+
+            P::write_status_response(&mut connection, status).await?;
+
+            trace!("Closing connection");
+            connection.shutdown().await?;
+        }
+        _ => {
+            let login_start = P::read_login_start(&mut connection).await?;
+            tracing::Span::current().record("username", &login_start.username);
+            trace!(?login_start, "Recieved login start packet");
 
-    config
-        .hosts
-        .get(hostname)
-        .or_else(|| config.get_default_host())
-        .map(|host| &host.action)
-        .cloned()
+            P::write_disconnect(&mut connection, multi_version::Disconnect { reason }).await?;
+
+            trace!("Closing connection");
+            connection.shutdown().await?;
+        }
+    }
+
+    Ok(())
 }
 
-fn blocking_proxy(
-    client_addr: &SocketAddr,
+/// Record the host a request ended up with, once any custom actions and
+/// scripts have handed it off.
+fn track_host(client: &Client, host: Option<&Hostname>, kind: &str) {
+    if let Some(host) = host {
+        client.tracked.set_host(host);
+    }
+
+    let host_label = host.map(Hostname::to_string).unwrap_or_default();
+    metrics::REQUESTS
+        .with_label_values(&[&host_label, kind])
+        .inc();
+}
+
+/// The versions supported by the host for a hostname, when the client's
+/// protocol isn't one of them.
+fn unsupported(
+    router: &Inner,
+    listener: &SocketAddr,
+    hostname: &Hostname,
+    protocol_version: i32,
+) -> Option<ProtocolRange> {
+    let config = router.config.read().unwrap();
+    let host = config.find_host(listener, hostname)?;
+
+    if host.supports(protocol_version) {
+        None
+    } else {
+        host.supported.clone()
+    }
+}
+
+fn unsupported_message(supported: &ProtocolRange) -> String {
+    format!("This server supports Minecraft {}", supported.releases())
+}
+
+/// Find the action for a hostname and protocol version, along with the name of
+/// the virtual host it came from (which is the default host's when there's no
+/// exact match).
+fn find_action(
+    router: &Inner,
+    listener: &SocketAddr,
+    hostname: &Hostname,
+    protocol_version: i32,
+) -> Option<(Hostname, Action)> {
+    let config = router.config.read().unwrap();
+
+    config.find_host(listener, hostname).map(|host| {
+        (
+            host.hostname.clone(),
+            host.action_for(protocol_version).clone(),
+        )
+    })
+}
+
+fn custom_request<'a>(
+    client: &Client,
+    handshake: &'a handshake::Handshake,
+    hostname: &Hostname,
+    host: Option<&Hostname>,
+    login_start: Option<&'a LoginStart>,
+) -> custom::Request<'a> {
+    custom::Request {
+        addr: client.addr,
+        listener: client.listener,
+        host: host.unwrap_or(hostname).clone(),
+        hostname: hostname.clone(),
+        port: handshake.server_port,
+        protocol_version: handshake.protocol_version,
+        handshake,
+        login_start,
+    }
+}
+
+/// Ask a custom action where the connection goes, `None` when there's no
+/// action by that name and the connection should be closed.
+fn run_custom(router: &Inner, name: &str, request: &custom::Request<'_>) -> Option<Route> {
+    let action = match router.action(name) {
+        Some(action) => action,
+        None => {
+            error!(
+                "No custom action named {} is registered, closing connection",
+                name
+            );
+            return None;
+        }
+    };
+
+    let route = action.route(request);
+    debug!(?route, "Custom action {} routed connection", name);
+    Some(route)
+}
+
+/// Ask a script where the connection goes, `None` when it didn't say.
+#[cfg(feature = "scripting")]
+async fn run_script(
+    router: &Inner,
+    script: &Path,
+    request: &custom::Request<'_>,
+    host: Option<&Hostname>,
+) -> Result<Option<Route>, String> {
+    scripting::run(router, script, request, host).await
+}
+
+#[cfg(not(feature = "scripting"))]
+async fn run_script(
+    _router: &Inner,
+    script: &Path,
+    _request: &custom::Request<'_>,
+    _host: Option<&Hostname>,
+) -> Result<Option<Route>, String> {
+    Err(format!(
+        "can't run script {}, the router was built without the `scripting` feature",
+        script.display()
+    ))
+}
+
+/// The action for a connection, which is the top level script's pick when
+/// there's a script and it makes one, and the virtual host's otherwise.
+///
+/// Custom actions and scripts are asked until one routes the connection
+/// somewhere, `None` when it should be closed instead. `host` is left as the
+/// last host they handed the connection to.
+async fn choose_action(
+    router: &Inner,
+    mut request: custom::Request<'_>,
+    host: &mut Option<Hostname>,
+    found: Option<(Hostname, Action)>,
+    script: Option<PathBuf>,
+) -> Option<Action> {
+    let mut action = None;
+
+    if let Some(script) = script {
+        match run_script(router, &script, &request, host.as_ref()).await {
+            Ok(Some(route)) => {
+                action = Some(route_action(router, route, &mut request, host)?);
+            }
+            Ok(None) => {}
+            Err(err) => error!("Routing by host instead, {}", err),
+        }
+    }
+
+    let mut action = match action.or_else(|| found.map(|(_, action)| action)) {
+        Some(action) => action,
+        None => {
+            info!("No action found for {}", request.hostname);
+            return None;
+        }
+    };
+
+    let status = request.login_start.is_none();
+    for _ in 0..=MAX_HAND_OFFS {
+        let route = match Decider::of(&action, status) {
+            None => return Some(action),
+            Some(Decider::Custom(name)) => run_custom(router, &name, &request)?,
+            Some(Decider::Script(script)) => {
+                match run_script(router, &script, &request, host.as_ref()).await {
+                    Ok(Some(route)) => route,
+                    Ok(None) => {
+                        info!(
+                            "Script {} didn't route connection, closing it",
+                            script.display()
+                        );
+                        return None;
+                    }
+                    Err(err) => {
+                        error!("Closing connection, {}", err);
+                        return None;
+                    }
+                }
+            }
+        };
+        action = route_action(router, route, &mut request, host)?;
+    }
+
+    if Decider::of(&action, status).is_none() {
+        return Some(action);
+    }
+    error!(
+        "Custom actions and scripts handed off more than {} times, closing connection",
+        MAX_HAND_OFFS
+    );
+    None
+}
+
+/// What gets asked where a connection goes instead of the config saying.
+enum Decider {
+    Custom(String),
+    Script(PathBuf),
+}
+
+impl Decider {
+    /// The decider for the status or login half of `action`, if it has one.
+    fn of(action: &Action, status: bool) -> Option<Self> {
+        if status {
+            match action.get_status_action() {
+                StatusAction::Custom { custom } => Some(Decider::Custom(custom)),
+                StatusAction::Script { script } => Some(Decider::Script(script)),
+                _ => None,
+            }
+        } else {
+            match action.get_login_action() {
+                LoginAction::Custom { custom } => Some(Decider::Custom(custom)),
+                LoginAction::Script { script } => Some(Decider::Script(script)),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// The action a custom action or script's route works out to, `None` when it
+/// names a host that doesn't exist. Handing off to a host makes it the one
+/// the connection is for, and refuses it if the host doesn't support the
+/// client's version.
+fn route_action(
+    router: &Inner,
+    route: Route,
+    request: &mut custom::Request<'_>,
+    host: &mut Option<Hostname>,
+) -> Option<Action> {
+    Some(match route {
+        Route::Forward(target) => Action::Forward {
+            forward: ForwardAction(target),
+        },
+        Route::Static(r#static) => Action::Static { r#static },
+        Route::Kick(message) => Action::Static {
+            r#static: StaticAction {
+                description: Some(message.clone()),
+                kick_message: Some(message),
+                ..Default::default()
+            },
+        },
+        Route::Host(hostname) => {
+            let (found, action) = match find_action(
+                router,
+                &request.listener,
+                &hostname,
+                request.protocol_version,
+            ) {
+                Some(found) => found,
+                None => {
+                    info!("No action found for {}", hostname);
+                    return None;
+                }
+            };
+            request.host = found.clone();
+            *host = Some(found);
+
+            // refused the same way as connecting to the host directly
+            match unsupported(
+                router,
+                &request.listener,
+                &hostname,
+                request.protocol_version,
+            ) {
+                Some(supported) => {
+                    info!(
+                        "Protocol {} isn't supported by {}, refusing connection",
+                        request.protocol_version, supported
+                    );
+                    let message = unsupported_message(&supported);
+                    Action::Static {
+                        r#static: StaticAction {
+                            version_name: Some(supported.releases()),
+                            protocol_version: Some(supported.nearest(request.protocol_version)),
+                            cur_players: Some(0),
+                            max_players: Some(0),
+                            description: Some(message.clone()),
+                            kick_message: Some(message),
+                        },
+                    }
+                }
+                None => action,
+            }
+        }
+    })
+}
+
+fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Connect to a forward target, trying each address it resolves to in turn.
+async fn connect_backend(
+    target: &ServerAddr,
+    connect_timeout: Option<Duration>,
+) -> io::Result<Connection> {
+    let result = connect_any(target, connect_timeout).await;
+    if result.is_err() {
+        metrics::BACKEND_CONNECT_FAILURES
+            .with_label_values(&[&target.to_string()])
+            .inc();
+    }
+
+    result
+}
+
+async fn connect_any(
+    target: &ServerAddr,
+    connect_timeout: Option<Duration>,
+) -> io::Result<Connection> {
+    let mut last_err = None;
+    for addr in net::lookup_host(target.to_string()).await? {
+        match with_timeout(connect_timeout, TcpStream::connect(addr)).await {
+            Ok(stream) => {
+                // only until the handshake is sent, proxy doesn't time out writes
+                let deadline = connect_timeout.map(|timeout| Instant::now() + timeout);
+                return Ok(Connection::new(stream, deadline));
+            }
+            Err(err) => {
+                debug!(%addr, %err, "Failed to connect to target address");
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{target} did not resolve to any addresses"),
+        )
+    }))
+}
+
+/// Copy between the client and server until either side closes or goes idle.
+async fn proxy(
+    client: &Client,
     client_stream: TcpStream,
     server: TcpStream,
+    target: &ServerAddr,
 ) -> color_eyre::Result<()> {
-    let client_read = client_stream;
-    let client_write = client_read.try_clone()?;
-
-    let server_read = server;
-    let server_write = server_read.try_clone()?;
-
-    let cs_thread = spawn_copy_thread(
-        format!("client({}) c->s", client_addr),
-        client_read,
-        server_write,
-    )?;
-    let sc_thread = spawn_copy_thread(
-        format!("client({}) s->c", client_addr),
-        server_read,
-        client_write,
-    )?;
-
-    cs_thread.join().unwrap();
-    sc_thread.join().unwrap();
+    let idle_timeout = client.router.config.read().unwrap().timeouts.idle();
+    let _session = shutdown::track_session(&client.router);
+    let _active_session = metrics::track_session(&target.to_string());
+    client.tracked.set_backend(target);
+
+    let sent = metrics::BYTES_PROXIED.with_label_values(&["client_to_server"]);
+    let received = metrics::BYTES_PROXIED.with_label_values(&["server_to_client"]);
+    let count_sent = |len| {
+        sent.inc_by(len);
+        client.tracked.add_sent(len);
+    };
+    let count_received = |len| {
+        received.inc_by(len);
+        client.tracked.add_received(len);
+    };
+
+    // a side closing only ends its direction, the session ends once both
+    // have or as soon as either fails
+    let client_to_server = async {
+        let result = pump::copy(&client_stream, &server, idle_timeout, count_sent).await;
+        end_copy("Client", &client.addr, &server, result)
+    };
+    let server_to_client = async {
+        let result = pump::copy(&server, &client_stream, idle_timeout, count_received).await;
+        end_copy("Server", &client.addr, &client_stream, result)
+    };
+    let _ = tokio::try_join!(client_to_server, server_to_client);
 
     Ok(())
 }
 
-fn spawn_copy_thread(
-    name: String,
-    mut from: TcpStream,
-    mut to: TcpStream,
-) -> color_eyre::Result<thread::JoinHandle<()>> {
-    Ok(thread::Builder::new().name(name).spawn(move || {
-        // todo: don't ignore all errors we recieve
-        let _ = io::copy(&mut from, &mut to);
-        let _ = to.shutdown(Shutdown::Both);
-    })?)
+/// Pass on that one side is done sending, or end the session if copying failed.
+fn end_copy(
+    side: &str,
+    client_addr: &SocketAddr,
+    to: &TcpStream,
+    result: io::Result<u64>,
+) -> io::Result<()> {
+    match result {
+        Ok(_) => {
+            trace!(%client_addr, "{side} finished sending");
+            // already closed by the other side is fine too
+            let _ = SockRef::from(to).shutdown(Shutdown::Write);
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+            debug!("Proxied session idle, closing");
+            Err(err)
+        }
+        Err(err) => {
+            trace!(%client_addr, %err, "{side} closed proxied session");
+            Err(err)
+        }
+    }
 }
@@ -1,14 +1,14 @@
-use std::{
-    convert::{Into, TryFrom},
-    io::Write,
-    net::SocketAddr,
-};
+use std::convert::Into;
 
-use mcproto::{error, handshake, packet, role, state, stdio::StdIoConnection, uuid::Uuid};
+use mcproto::{handshake, packet, types::BufType, uuid::Uuid};
+use tokio::io::AsyncWriteExt;
 use tracing::debug;
 use type_map::concurrent::TypeMap;
 
-use crate::{client::blocking_proxy, config::ServerAddr};
+use crate::{
+    client::{connect_backend, connection::Connection, proxy, Client},
+    config::ServerAddr,
+};
 
 #[derive(Debug)]
 pub struct StatusRequest;
@@ -58,31 +58,11 @@ pub struct PingResponse {
     pub payload: i64,
 }
 
-pub trait StatusState:
-    state::ProtocolState
-    + state::RoleStatePackets<role::Server>
-    + state::NextProtocolState<handshake::HandshakingState>
-    + Sized
-where
-    Self::RecvPacket: packet::PacketFromIdBody,
-{
-    type StatusRequest: packet::Packet
-        + state::RoleStateReadPacket<role::Server, Self>
-        + TryFrom<Self::RecvPacket, Error = error::Error>
-        + Into<StatusRequest>;
-
-    type StatusResponse: packet::Packet
-        + state::RoleStateWritePacket<role::Server, Self>
-        + From<StatusResponse>;
-
-    type PingRequest: packet::Packet
-        + state::RoleStateReadPacket<role::Server, Self>
-        + TryFrom<Self::RecvPacket, Error = error::Error>
-        + Into<PingRequest>;
-
-    type PingResponse: packet::Packet
-        + state::RoleStateWritePacket<role::Server, Self>
-        + From<PingResponse>;
+pub trait StatusState {
+    type StatusRequest: packet::Packet + BufType + Into<StatusRequest>;
+    type StatusResponse: packet::Packet + BufType + From<StatusResponse>;
+    type PingRequest: packet::Packet + BufType + Into<PingRequest>;
+    type PingResponse: packet::Packet + BufType + From<PingResponse>;
 }
 
 #[derive(Debug)]
@@ -104,156 +84,117 @@ impl Disconnect {
     }
 }
 
-pub trait LoginState:
-    state::ProtocolState
-    + state::RoleStatePackets<role::Server>
-    + state::NextProtocolState<handshake::HandshakingState>
-    + Sized
-where
-    Self::RecvPacket: packet::PacketFromIdBody,
-{
-    type LoginStart: packet::Packet
-        + state::RoleStateReadPacket<role::Server, Self>
-        + TryFrom<Self::RecvPacket, Error = error::Error>
-        + Into<LoginStart>
-        + state::RoleStateWritePacket<role::Client, Self>
-        + From<LoginStart>;
-
-    type Disconnect: packet::Packet
-        + state::RoleStateWritePacket<role::Server, Self>
-        + From<Disconnect>;
+pub trait LoginState {
+    type LoginStart: packet::Packet + BufType + Into<LoginStart> + From<LoginStart>;
+    type Disconnect: packet::Packet + BufType + From<Disconnect>;
 }
 
-pub trait Protocol
-where
-    <Self::StatusState as state::RoleStatePackets<role::Server>>::RecvPacket:
-        packet::PacketFromIdBody,
-
-    <Self::LoginState as state::RoleStatePackets<role::Server>>::RecvPacket:
-        packet::PacketFromIdBody,
-{
+pub trait Protocol {
     const VERSION: i32;
 
     type StatusState: StatusState;
     type LoginState: LoginState;
 
-    fn status_state(
-        connection: StdIoConnection<role::Server, handshake::HandshakingState>,
-    ) -> StdIoConnection<role::Server, Self::StatusState> {
-        connection.next_state()
-    }
-
-    fn read_status_request(
-        connection: &mut StdIoConnection<role::Server, Self::StatusState>,
-    ) -> color_eyre::Result<StatusRequest> {
+    async fn read_status_request(connection: &mut Connection) -> color_eyre::Result<StatusRequest> {
         let request: <Self::StatusState as StatusState>::StatusRequest =
-            connection.expect_next_packet()?;
+            connection.expect_next_packet().await?;
         Ok(request.into())
     }
 
-    fn write_status_response(
-        connection: &mut StdIoConnection<role::Server, Self::StatusState>,
+    async fn write_status_response(
+        connection: &mut Connection,
         status_response: StatusResponse,
     ) -> color_eyre::Result<()> {
-        connection.write_packet(
-            Into::<<Self::StatusState as StatusState>::StatusResponse>::into(status_response),
-        )?;
-        Ok(())
+        connection
+            .write_packet(
+                Into::<<Self::StatusState as StatusState>::StatusResponse>::into(status_response),
+            )
+            .await
     }
-    fn read_ping_request(
-        connection: &mut StdIoConnection<role::Server, Self::StatusState>,
-    ) -> color_eyre::Result<PingRequest> {
+
+    async fn read_ping_request(connection: &mut Connection) -> color_eyre::Result<PingRequest> {
         let request: <Self::StatusState as StatusState>::PingRequest =
-            connection.expect_next_packet()?;
+            connection.expect_next_packet().await?;
         Ok(request.into())
     }
 
-    fn write_ping_response(
-        connection: &mut StdIoConnection<role::Server, Self::StatusState>,
+    async fn write_ping_response(
+        connection: &mut Connection,
         status_response: PingResponse,
     ) -> color_eyre::Result<()> {
-        connection.write_packet(
-            Into::<<Self::StatusState as StatusState>::PingResponse>::into(status_response),
-        )?;
-        Ok(())
+        connection
+            .write_packet(
+                Into::<<Self::StatusState as StatusState>::PingResponse>::into(status_response),
+            )
+            .await
     }
 
-    fn forward_status(
-        connection: StdIoConnection<role::Server, Self::StatusState>,
-        addr: SocketAddr,
+    async fn forward_status(
+        connection: Connection,
+        client: &Client,
         handshake: handshake::Handshake,
         target: ServerAddr,
     ) -> color_eyre::Result<()> {
         // todo log
         debug!("Connecting to {:?}", target);
-        let mut server =
-            mcproto::stdio::connect_stdio_stream::<_, role::Client, handshake::HandshakingState>(
-                &target,
-            )?;
+        let connect_timeout = client.router.config.read().unwrap().timeouts.connect();
+        let mut server = connect_backend(&target, Some(connect_timeout)).await?;
 
         // TODO: add config option to re write handshake to include target hostname/port
-        server.write_packet(handshake)?;
+        server.write_packet(handshake).await?;
 
         let (server_bytes, mut server) = server.into_bytes_stream();
-        let (client_bytes, mut client) = connection.into_bytes_stream();
-
-        client.write_all(&server_bytes)?;
-        server.write_all(&client_bytes)?;
+        let (client_bytes, mut client_stream) = connection.into_bytes_stream();
 
-        blocking_proxy(&addr, client, server)
-    }
+        client_stream.write_all(&server_bytes).await?;
+        server.write_all(&client_bytes).await?;
 
-    fn login_state(
-        connection: StdIoConnection<role::Server, handshake::HandshakingState>,
-    ) -> StdIoConnection<role::Server, Self::LoginState> {
-        connection.next_state()
+        proxy(client, client_stream, server, &target).await
     }
 
-    fn read_login_start(
-        connection: &mut StdIoConnection<role::Server, Self::LoginState>,
-    ) -> color_eyre::Result<LoginStart> {
+    async fn read_login_start(connection: &mut Connection) -> color_eyre::Result<LoginStart> {
         let login_start: <Self::LoginState as LoginState>::LoginStart =
-            connection.expect_next_packet()?;
+            connection.expect_next_packet().await?;
         Ok(login_start.into())
     }
 
-    fn write_disconnect(
-        connection: &mut StdIoConnection<role::Server, Self::LoginState>,
+    async fn write_disconnect(
+        connection: &mut Connection,
         disconnect: Disconnect,
     ) -> color_eyre::Result<()> {
-        connection.write_packet(Into::<<Self::LoginState as LoginState>::Disconnect>::into(
-            disconnect,
-        ))?;
-        Ok(())
+        connection
+            .write_packet(Into::<<Self::LoginState as LoginState>::Disconnect>::into(
+                disconnect,
+            ))
+            .await
     }
 
-    fn forward_login(
-        connection: StdIoConnection<role::Server, Self::LoginState>,
-        addr: SocketAddr,
+    async fn forward_login(
+        connection: Connection,
+        client: &Client,
         handshake: handshake::Handshake,
         login_start: LoginStart,
         target: ServerAddr,
     ) -> color_eyre::Result<()> {
         // todo log
         debug!("Connecting to {:?}", target);
-        let mut server =
-            mcproto::stdio::connect_stdio_stream::<_, role::Client, handshake::HandshakingState>(
-                &target,
-            )?;
+        let connect_timeout = client.router.config.read().unwrap().timeouts.connect();
+        let mut server = connect_backend(&target, Some(connect_timeout)).await?;
 
         // TODO: add config option to re write handshake to include target hostname/port
-        server.write_packet(handshake)?;
-        let mut server = server.next_state::<Self::LoginState>();
-        server.write_packet(Into::<<Self::LoginState as LoginState>::LoginStart>::into(
-            login_start,
-        ))?;
+        server.write_packet(handshake).await?;
+        server
+            .write_packet(Into::<<Self::LoginState as LoginState>::LoginStart>::into(
+                login_start,
+            ))
+            .await?;
 
         let (server_bytes, mut server) = server.into_bytes_stream();
-        let (client_bytes, mut client) = connection.into_bytes_stream();
+        let (client_bytes, mut client_stream) = connection.into_bytes_stream();
 
-        client.write_all(&server_bytes)?;
-        server.write_all(&client_bytes)?;
+        client_stream.write_all(&server_bytes).await?;
+        server.write_all(&client_bytes).await?;
 
-        blocking_proxy(&addr, client, server)
+        proxy(client, client_stream, server, &target).await
     }
 }
@@ -0,0 +1,73 @@
+//! Server list ping from the client side, for checking on a server from the command line.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Buf;
+use color_eyre::{eyre::eyre, Result};
+use mcproto::handshake;
+
+use super::{
+    connect_backend, connection::read_varint, multi_version::Protocol, version_impls::ProtocolV767,
+};
+use crate::config::{ServerAddr, Timeouts};
+
+static STATUS_ID: i32 = 0x00;
+static PING_ID: i32 = 0x01;
+
+#[derive(Debug)]
+pub struct Pong {
+    /// Status json exactly as the server sent it.
+    pub status: String,
+    pub latency: Duration,
+}
+
+pub async fn ping(target: &ServerAddr) -> Result<Pong> {
+    let connect_timeout = Timeouts::default().connect();
+    let mut connection = connect_backend(target, Some(connect_timeout)).await?;
+
+    connection
+        .write_packet(handshake::Handshake {
+            protocol_version: ProtocolV767::VERSION,
+            server_address: target.hostname().to_string(),
+            server_port: target.port(),
+            next_state: handshake::NextState::Status,
+        })
+        .await?;
+    connection.write_frame(STATUS_ID, &[]).await?;
+
+    let (packet_id, mut body) = connection.read_frame().await?;
+    if packet_id != STATUS_ID {
+        return Err(eyre!(
+            "expected a status response, got packet {:#04x}",
+            packet_id
+        ));
+    }
+
+    let (len, len_len) =
+        read_varint(&body)?.ok_or_else(|| eyre!("status response is missing its length"))?;
+    body.advance(len_len);
+    let len = len as usize;
+    if body.len() < len {
+        return Err(eyre!("status response is shorter than its length"));
+    }
+    let status = String::from_utf8(body[..len].to_vec())?;
+
+    // vanilla sends the current time, servers just echo it back
+    let payload = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as i64);
+
+    let sent = Instant::now();
+    connection
+        .write_frame(PING_ID, &payload.to_be_bytes())
+        .await?;
+    let (packet_id, _) = connection.read_frame().await?;
+    if packet_id != PING_ID {
+        return Err(eyre!("expected a pong, got packet {:#04x}", packet_id));
+    }
+
+    Ok(Pong {
+        status,
+        latency: sent.elapsed(),
+    })
+}
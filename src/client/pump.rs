@@ -0,0 +1,189 @@
+// Kept free of the rest of the crate so benches/proxy_throughput.rs can include it directly.
+
+use std::{future::Future, io, time::Duration};
+
+use tokio::{net::TcpStream, time};
+
+static BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copy everything from one socket to the other until `from` closes, errors
+/// or receives nothing for `idle_timeout`, calling `copied` as each chunk is
+/// written.
+///
+/// Uses `splice(2)` on Linux and falls back to copying through a userspace
+/// buffer everywhere else, or if a pipe can't be created.
+pub async fn copy(
+    from: &TcpStream,
+    to: &TcpStream,
+    idle_timeout: Option<Duration>,
+    copied: impl FnMut(u64),
+) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    match splice::Pipe::new() {
+        Ok(pipe) => return splice::copy(from, to, idle_timeout, pipe, copied).await,
+        Err(err) => {
+            tracing::debug!(%err, "Couldn't create pipe, falling back to buffered copy")
+        }
+    }
+
+    copy_buffered(from, to, idle_timeout, copied).await
+}
+
+pub async fn copy_buffered(
+    from: &TcpStream,
+    to: &TcpStream,
+    idle_timeout: Option<Duration>,
+    mut copied: impl FnMut(u64),
+) -> io::Result<u64> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut total = 0;
+
+    loop {
+        let len = idle(idle_timeout, async {
+            loop {
+                from.readable().await?;
+                match from.try_read(&mut buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    result => return result,
+                }
+            }
+        })
+        .await?;
+
+        if len == 0 {
+            return Ok(total);
+        }
+
+        let mut written = 0;
+        while written < len {
+            to.writable().await?;
+            match to.try_write(&buf[written..len]) {
+                Ok(len) => written += len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        copied(len as u64);
+        total += len as u64;
+    }
+}
+
+async fn idle<T, F>(idle_timeout: Option<Duration>, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match idle_timeout {
+        Some(idle_timeout) => time::timeout(idle_timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => future.await,
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod splice {
+    use std::{
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        ptr,
+        time::Duration,
+    };
+
+    use tokio::{io::Interest, net::TcpStream};
+
+    use super::{idle, BUFFER_SIZE};
+
+    /// Both ends of a non-blocking pipe, bytes are moved into it from one
+    /// socket and straight back out to the other without touching userspace.
+    #[derive(Debug)]
+    pub struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` is valid for the two descriptors pipe2 writes
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // SAFETY: pipe2 succeeded so both descriptors are open and owned by us
+            Ok(unsafe {
+                Pipe {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                }
+            })
+        }
+    }
+
+    pub async fn copy(
+        from: &TcpStream,
+        to: &TcpStream,
+        idle_timeout: Option<Duration>,
+        pipe: Pipe,
+        mut copied: impl FnMut(u64),
+    ) -> io::Result<u64> {
+        let mut total = 0;
+
+        loop {
+            // the pipe is always drained below, so `WouldBlock` here only
+            // ever means the socket has nothing to read
+            let len = idle(idle_timeout, async {
+                loop {
+                    from.readable().await?;
+                    match from.try_io(Interest::READABLE, || {
+                        splice(from.as_raw_fd(), pipe.write.as_raw_fd(), BUFFER_SIZE)
+                    }) {
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                        result => return result,
+                    }
+                }
+            })
+            .await?;
+
+            if len == 0 {
+                return Ok(total);
+            }
+
+            let mut remaining = len;
+            while remaining > 0 {
+                to.writable().await?;
+                match to.try_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), remaining)
+                }) {
+                    Ok(len) => remaining -= len,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            copied(len as u64);
+            total += len as u64;
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: both descriptors are open for the duration of the call and
+        // null offsets are valid for sockets and pipes
+        let len = unsafe {
+            libc::splice(
+                from,
+                ptr::null_mut(),
+                to,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+
+        if len == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(len as usize)
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use mcproto::handshake::NextState;
+
+use crate::{
+    config::{self, RateLimit},
+    router::Inner,
+};
+
+static DEFAULT_IPV4_PREFIX: u8 = 24;
+static DEFAULT_IPV6_PREFIX: u8 = 48;
+static PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// connections over the cap waiting to be sent the full message, past this
+// they're closed straight away
+static MAX_REJECTING: usize = 64;
+
+/// A router's token buckets and how many connections it has open.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    active_connections: AtomicUsize,
+    rejecting: AtomicUsize,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+            active_connections: AtomicUsize::new(0),
+            rejecting: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Status,
+    Login,
+}
+
+impl From<&NextState> for Kind {
+    fn from(next_state: &NextState) -> Self {
+        match next_state {
+            NextState::Status => Kind::Status,
+            _ => Kind::Login,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Scope {
+    Ip,
+    Subnet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    /// The router is already at its connection cap.
+    Full,
+    /// The client (or its subnet) ran out of tokens.
+    TooFast,
+}
+
+impl Throttle {
+    pub fn message(&self, limits: &RateLimit) -> String {
+        #[allow(clippy::or_fun_call)]
+        match self {
+            Throttle::Full => limits
+                .full_message
+                .clone()
+                .unwrap_or("The server is full, please try again later".into()),
+            Throttle::TooFast => limits
+                .throttle_message
+                .clone()
+                .unwrap_or("You are connecting too fast, please wait and try again".into()),
+        }
+    }
+}
+
+/// Held for the lifetime of a client connection, counts towards `maxconnections`.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    counted: bool,
+    router: Arc<Inner>,
+}
+
+/// Admit a freshly accepted connection, before anything is spawned for it.
+///
+/// A few connections over the cap are still handed a permit so they can be
+/// sent a kick message, but they aren't counted and every check on them
+/// fails. Returns `None` when there are already enough of those, the
+/// connection should be closed without handling it at all.
+pub fn admit(router: &Arc<Inner>, addr: SocketAddr) -> Option<ConnectionPermit> {
+    let max_connections = router.config.read().unwrap().rate_limit.max_connections;
+    let limits = &router.limits;
+    let active = limits.active_connections.fetch_add(1, Ordering::SeqCst);
+
+    let counted = match max_connections {
+        Some(max) if active >= max => {
+            limits.active_connections.fetch_sub(1, Ordering::SeqCst);
+            false
+        }
+        _ => true,
+    };
+
+    if !counted && limits.rejecting.fetch_add(1, Ordering::SeqCst) >= MAX_REJECTING {
+        limits.rejecting.fetch_sub(1, Ordering::SeqCst);
+        return None;
+    }
+
+    Some(ConnectionPermit {
+        ip: addr.ip().to_canonical(),
+        counted,
+        router: router.clone(),
+    })
+}
+
+impl ConnectionPermit {
+    /// Take a token for this connection's ip and subnet.
+    pub fn check(&self, kind: Kind) -> Option<Throttle> {
+        if !self.counted {
+            return Some(Throttle::Full);
+        }
+
+        let config = self.router.config.read().unwrap();
+        let limits = &config.rate_limit;
+
+        if self
+            .router
+            .limits
+            .buckets
+            .lock()
+            .unwrap()
+            .take(limits, kind, self.ip)
+        {
+            None
+        } else {
+            Some(Throttle::TooFast)
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let limits = &self.router.limits;
+        if self.counted {
+            limits.active_connections.fetch_sub(1, Ordering::SeqCst);
+        } else {
+            limits.rejecting.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &config::TokenBucket, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            last_update: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &config::TokenBucket, now: Instant) {
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.last_update = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<(Kind, Scope, IpAddr), TokenBucket>,
+    last_prune: Instant,
+}
+
+impl Buckets {
+    /// Take a token from both the ip's and the subnet's bucket, or from
+    /// neither if either of them is empty.
+    fn take(&mut self, limits: &RateLimit, kind: Kind, ip: IpAddr) -> bool {
+        self.take_at(limits, kind, ip, Instant::now())
+    }
+
+    fn take_at(&mut self, limits: &RateLimit, kind: Kind, ip: IpAddr, now: Instant) -> bool {
+        self.maybe_prune(limits, now);
+
+        let subnet = subnet_of(limits, ip);
+        let keys = [(Scope::Ip, ip), (Scope::Subnet, subnet)]
+            .iter()
+            .filter_map(|&(scope, addr)| {
+                bucket_limit(limits, kind, scope).map(|limit| ((kind, scope, addr), limit))
+            })
+            .collect::<Vec<_>>();
+
+        for (key, limit) in &keys {
+            self.buckets
+                .entry(*key)
+                .or_insert_with(|| TokenBucket::full(limit, now))
+                .refill(limit, now);
+        }
+
+        if keys.iter().any(|(key, _)| self.buckets[key].tokens < 1.0) {
+            return false;
+        }
+        for (key, _) in &keys {
+            self.buckets.get_mut(key).unwrap().tokens -= 1.0;
+        }
+        true
+    }
+
+    /// Drop buckets that have refilled completely, they're the same as a new one.
+    fn maybe_prune(&mut self, limits: &RateLimit, now: Instant) {
+        if now.duration_since(self.last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_prune = now;
+
+        self.buckets.retain(
+            |(kind, scope, _), bucket| match bucket_limit(limits, *kind, *scope) {
+                Some(limit) => {
+                    bucket.refill(&limit, now);
+                    bucket.tokens < limit.burst
+                }
+                None => false,
+            },
+        );
+    }
+}
+
+fn bucket_limit(limits: &RateLimit, kind: Kind, scope: Scope) -> Option<config::TokenBucket> {
+    let buckets = match kind {
+        Kind::Status => &limits.status,
+        Kind::Login => &limits.login,
+    };
+
+    match scope {
+        Scope::Ip => buckets.per_ip,
+        Scope::Subnet => buckets.per_subnet,
+    }
+}
+
+fn subnet_of(limits: &RateLimit, ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let prefix = limits.ipv4_prefix.unwrap_or(DEFAULT_IPV4_PREFIX).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let prefix = limits.ipv6_prefix.unwrap_or(DEFAULT_IPV6_PREFIX).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_ip: f64, per_subnet: f64) -> RateLimit {
+        let bucket = |burst| Some(config::TokenBucket { rate: 0.001, burst });
+
+        RateLimit {
+            login: config::RateLimitBuckets {
+                per_ip: bucket(per_ip),
+                per_subnet: bucket(per_subnet),
+            },
+            ..Default::default()
+        }
+    }
+
+    fn buckets() -> Buckets {
+        Buckets {
+            buckets: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn empty_subnet_leaves_ip_bucket_alone() {
+        let limits = limits(2.0, 1.0);
+        let mut buckets = buckets();
+        let now = Instant::now();
+        let first = "10.0.0.1".parse().unwrap();
+        let second = "10.0.0.2".parse().unwrap();
+
+        assert!(buckets.take_at(&limits, Kind::Login, first, now));
+        // the subnet is empty now, so this shouldn't cost the ip a token
+        assert!(!buckets.take_at(&limits, Kind::Login, second, now));
+
+        let ip = &buckets.buckets[&(Kind::Login, Scope::Ip, second)];
+        assert_eq!(ip.tokens, 2.0);
+    }
+
+    #[test]
+    fn kinds_and_subnets_have_separate_buckets() {
+        let limits = limits(1.0, 1.0);
+        let mut buckets = buckets();
+        let now = Instant::now();
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert!(buckets.take_at(&limits, Kind::Login, ip, now));
+        assert!(!buckets.take_at(&limits, Kind::Login, ip, now));
+        // no status limits are configured
+        assert!(buckets.take_at(&limits, Kind::Status, ip, now));
+        assert!(buckets.take_at(&limits, Kind::Login, "10.0.1.1".parse().unwrap(), now));
+    }
+}
@@ -0,0 +1,225 @@
+//! Every open client connection, so they can be listed and kicked while running.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{
+    config::{Hostname, ServerAddr},
+    router::Inner,
+};
+
+/// A router's open connections by id.
+#[derive(Debug)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<Tracked>>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            next_id: AtomicU64::new(1),
+            connections: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// Shared state of one connection, filled in as the client gets further along.
+#[derive(Debug)]
+pub struct Tracked {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub listener: SocketAddr,
+    started: Instant,
+    details: Mutex<Details>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    kick: Notify,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Details {
+    username: Option<String>,
+    protocol_version: Option<i32>,
+    hostname: Option<Hostname>,
+    host: Option<Hostname>,
+    backend: Option<ServerAddr>,
+}
+
+/// A point in time view of a connection.
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub listener: SocketAddr,
+    pub username: Option<String>,
+    pub protocol_version: Option<i32>,
+    /// Hostname the client connected with.
+    pub hostname: Option<Hostname>,
+    /// Virtual host handling the connection, which is the default host's when
+    /// the hostname didn't match any.
+    pub host: Option<Hostname>,
+    pub backend: Option<ServerAddr>,
+    /// Bytes sent from the client to its backend.
+    pub bytes_sent: u64,
+    /// Bytes sent from the backend to the client.
+    pub bytes_received: u64,
+    pub duration_secs: u64,
+}
+
+impl Tracked {
+    pub fn set_username(&self, username: &str) {
+        self.details.lock().unwrap().username = Some(username.to_owned());
+    }
+
+    pub fn set_handshake(&self, protocol_version: i32, hostname: Hostname) {
+        let mut details = self.details.lock().unwrap();
+        details.protocol_version = Some(protocol_version);
+        details.hostname = Some(hostname);
+    }
+
+    pub fn set_host(&self, host: &Hostname) {
+        self.details.lock().unwrap().host = Some(host.clone());
+    }
+
+    pub fn set_backend(&self, backend: &ServerAddr) {
+        self.details.lock().unwrap().backend = Some(backend.clone());
+    }
+
+    pub fn add_sent(&self, len: u64) {
+        self.bytes_sent.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, len: u64) {
+        self.bytes_received.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Resolves once the connection has been kicked, kicks made before this
+    /// is awaited aren't lost.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        let details = self.details.lock().unwrap().clone();
+
+        ConnectionInfo {
+            id: self.id,
+            addr: self.addr,
+            listener: self.listener,
+            username: details.username,
+            protocol_version: details.protocol_version,
+            hostname: details.hostname,
+            host: details.host,
+            backend: details.backend,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            duration_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Keeps a connection listed until dropped.
+#[derive(Debug)]
+pub struct Registration {
+    tracked: Arc<Tracked>,
+    router: Arc<Inner>,
+}
+
+pub fn register(router: &Arc<Inner>, addr: SocketAddr, listener: SocketAddr) -> Registration {
+    let registry = &router.connections;
+    let tracked = Arc::new(Tracked {
+        id: registry.next_id.fetch_add(1, Ordering::Relaxed),
+        addr,
+        listener,
+        started: Instant::now(),
+        details: Mutex::new(Details::default()),
+        bytes_sent: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+        kick: Notify::new(),
+    });
+
+    registry
+        .connections
+        .lock()
+        .unwrap()
+        .insert(tracked.id, tracked.clone());
+    Registration {
+        tracked,
+        router: router.clone(),
+    }
+}
+
+impl Registration {
+    /// Another handle to the same connection, which doesn't keep it listed.
+    pub fn handle(&self) -> Arc<Tracked> {
+        self.tracked.clone()
+    }
+}
+
+impl Deref for Registration {
+    type Target = Tracked;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tracked
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.router
+            .connections
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.tracked.id);
+    }
+}
+
+impl Registry {
+    /// All open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|tracked| tracked.info())
+            .collect()
+    }
+
+    /// Close both sides of a connection, returns false if there's no such connection.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(tracked) => {
+                tracked.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kick every connection being handled by a virtual host, returns how many were kicked.
+    pub fn kick_host(&self, host: &Hostname) -> usize {
+        let mut kicked = 0;
+
+        for tracked in self.connections.lock().unwrap().values() {
+            if tracked.details.lock().unwrap().host.as_ref() == Some(host) {
+                tracked.kick.notify_one();
+                kicked += 1;
+            }
+        }
+
+        kicked
+    }
+}
@@ -0,0 +1,811 @@
+//! Saving a yaml config by editing the file it was read from, so comments,
+//! formatting and the order of everything that didn't change are kept.
+//!
+//! The values being compared are the config as it's saved, rather than what's
+//! written in the file, the file is only used to find where each value is.
+//! Anything that changed is rewritten a whole key or list element at a time,
+//! new hosts and keys go after the last one already there.
+
+use serde_yaml::{Mapping, Value};
+use yaml_rust2::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+/// `source` edited to go from `old` to `new`, where `old` is what `source` was saved from.
+///
+/// Returns `None` if the file is written in a way that can't be edited in
+/// place, like anchors or everything on one line, it has to be rewritten then.
+pub fn apply(source: &str, old: &Value, new: &Value) -> Option<String> {
+    // the end of the last value is the start of the line after it
+    let mut source = source.to_owned();
+    if !source.ends_with('\n') {
+        source.push('\n');
+    }
+
+    let root = Node::parse(&source)?;
+    let (old, new) = match (old, new) {
+        (Value::Mapping(old), Value::Mapping(new)) => (old, new),
+        _ => return None,
+    };
+
+    let mut document = Document {
+        lines: source.lines().map(str::to_owned).collect(),
+        edits: Vec::new(),
+    };
+    match &root.kind {
+        Kind::Mapping(entries) if !root.flow => {
+            if !document.update_mapping(entries, old, new) {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+
+    Some(document.finish())
+}
+
+/// Where a value is in the file.
+#[derive(Debug)]
+struct Node {
+    start: Marker,
+    /// Start of whatever comes after the value.
+    end: Marker,
+    /// Written inside `{}` or `[]`.
+    flow: bool,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Scalar,
+    Mapping(Vec<Entry>),
+    Sequence(Vec<Node>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    mark: Marker,
+    value: Node,
+}
+
+#[derive(Default)]
+struct Events(Vec<(Event, Marker)>);
+
+impl MarkedEventReceiver for Events {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        self.0.push((event, mark));
+    }
+}
+
+impl Node {
+    /// The only document in `source`.
+    fn parse(source: &str) -> Option<Self> {
+        let mut events = Events::default();
+        Parser::new_from_str(source).load(&mut events, true).ok()?;
+
+        let documents = events
+            .0
+            .iter()
+            .filter(|(event, _)| matches!(event, Event::DocumentStart))
+            .count();
+        if documents != 1 {
+            return None;
+        }
+
+        let start = events
+            .0
+            .iter()
+            .position(|(event, _)| matches!(event, Event::DocumentStart))?;
+        let lines = source.lines().collect::<Vec<_>>();
+        Self::build(&events.0, &mut (start + 1), &lines)
+    }
+
+    fn build(events: &[(Event, Marker)], next: &mut usize, lines: &[&str]) -> Option<Self> {
+        let (event, start) = events.get(*next)?;
+        *next += 1;
+
+        // anchored values are used somewhere else too, changing them changes that
+        let kind = match event {
+            Event::Scalar(_, _, 0, _) => Kind::Scalar,
+            Event::MappingStart(0, _) => {
+                let mut entries = Vec::new();
+                loop {
+                    let (event, mark) = events.get(*next)?;
+                    *next += 1;
+
+                    match event {
+                        Event::MappingEnd => break,
+                        Event::Scalar(key, _, 0, _) => entries.push(Entry {
+                            key: key.clone(),
+                            mark: *mark,
+                            value: Self::build(events, next, lines)?,
+                        }),
+                        _ => return None,
+                    }
+                }
+                Kind::Mapping(entries)
+            }
+            Event::SequenceStart(0, _) => {
+                let mut elements = Vec::new();
+                while !matches!(events.get(*next)?.0, Event::SequenceEnd) {
+                    elements.push(Self::build(events, next, lines)?);
+                }
+                *next += 1;
+                Kind::Sequence(elements)
+            }
+            _ => return None,
+        };
+
+        let flow = matches!(
+            lines.get(start.line() - 1)?.chars().nth(start.col()),
+            Some('{' | '[')
+        );
+
+        // block mappings are marked wherever the scanner was, rather than the first key
+        let start = match &kind {
+            Kind::Mapping(entries) if !flow => entries.first()?.mark,
+            _ => *start,
+        };
+
+        Some(Node {
+            start,
+            end: events.get(*next)?.1,
+            flow,
+            kind,
+        })
+    }
+}
+
+/// Replace `lines[first..last]` with `with`.
+struct Edit {
+    first: usize,
+    last: usize,
+    with: Vec<String>,
+}
+
+struct Document {
+    lines: Vec<String>,
+    edits: Vec<Edit>,
+}
+
+impl Document {
+    fn finish(mut self) -> String {
+        // later edits to the same line were made for values after the earlier ones
+        let mut edits = std::mem::take(&mut self.edits)
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        edits.sort_by_key(|(i, edit)| std::cmp::Reverse((edit.first, *i)));
+
+        for (_, edit) in edits {
+            let removed = edit.with.is_empty() && edit.last > edit.first;
+            self.lines.splice(edit.first..edit.last, edit.with);
+            if removed {
+                self.close_gap(edit.first);
+            }
+        }
+
+        let mut source = self.lines.join("\n");
+        source.push('\n');
+        source
+    }
+
+    /// Drop the blank lines after removed lines when they'd leave a gap under
+    /// a key or double one up, and any left at the end of the file.
+    fn close_gap(&mut self, at: usize) {
+        let blank = |line: &String| line.trim().is_empty();
+        let mut end = at;
+        while end < self.lines.len() && blank(&self.lines[end]) {
+            end += 1;
+        }
+
+        let mut start = at;
+        if end == self.lines.len() {
+            while start > 0 && blank(&self.lines[start - 1]) {
+                start -= 1;
+            }
+        } else if start > 0
+            && !blank(&self.lines[start - 1])
+            && !self.lines[start - 1].trim_end().ends_with(':')
+        {
+            return;
+        }
+
+        self.lines.drain(start..end);
+    }
+
+    /// Change `node` from `old` to `new`, false if it has to be replaced as a whole.
+    fn update(&mut self, node: &Node, old: &Value, new: &Value) -> bool {
+        if old == new {
+            return true;
+        }
+        if node.flow {
+            return false;
+        }
+
+        match (&node.kind, old, new) {
+            (Kind::Mapping(entries), Value::Mapping(old), Value::Mapping(new)) => {
+                self.update_mapping(entries, old, new)
+            }
+            (Kind::Sequence(elements), Value::Sequence(old), Value::Sequence(new)) => {
+                self.update_sequence(elements, old, new)
+            }
+            _ => false,
+        }
+    }
+
+    fn update_mapping(&mut self, entries: &[Entry], old: &Mapping, new: &Mapping) -> bool {
+        let find = |key: &Value| {
+            key.as_str()
+                .and_then(|key| entries.iter().find(|entry| entry.key == key))
+        };
+
+        let mut added = Mapping::new();
+        let mut removed = 0;
+        for (key, value) in new {
+            let previous = old.get(key);
+            if previous == Some(value) {
+                continue;
+            }
+
+            match find(key) {
+                // unset, same as leaving it out
+                Some(entry) if value.is_null() && self.remove_entry(entry) => removed += 1,
+                Some(entry) => self.update_entry(entry, previous.unwrap_or(&Value::Null), value),
+                // left out of the file, and still the default
+                None if previous.is_none() && is_empty(value) => {}
+                None => {
+                    added.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        for key in old.keys().filter(|key| !new.contains_key(*key)) {
+            if let Some(entry) = find(key) {
+                if !self.remove_entry(entry) {
+                    return false;
+                }
+                removed += 1;
+            }
+        }
+
+        if !added.is_empty() {
+            let last = match entries.last() {
+                Some(last) => last,
+                None => return false,
+            };
+
+            let line = self.entry_end(last);
+            let indent = " ".repeat(last.mark.col());
+            self.insert(line, &indent, Value::Mapping(added));
+        } else if removed == entries.len() {
+            // a key with nothing under it is null rather than empty
+            return false;
+        }
+
+        true
+    }
+
+    fn update_sequence(&mut self, elements: &[Node], old: &[Value], new: &[Value]) -> bool {
+        if elements.len() != old.len() || new.is_empty() {
+            return false;
+        }
+        let (old_ids, new_ids) = match (identities(old), identities(new)) {
+            (Some(old_ids), Some(new_ids)) => (old_ids, new_ids),
+            // like version rules, the position is all there is to go on
+            _ => return self.update_positions(elements, old, new),
+        };
+
+        for (i, id) in old_ids.iter().enumerate() {
+            match new_ids.iter().position(|new_id| new_id == id) {
+                Some(j) => {
+                    if !self.update_element(&elements[i], &old[i], &new[j]) {
+                        return false;
+                    }
+                }
+                None => {
+                    if !self.remove_element(&elements[i]) {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        let added = new
+            .iter()
+            .zip(&new_ids)
+            .filter(|(_, id)| !old_ids.contains(id))
+            .map(|(value, _)| value.clone())
+            .collect::<Vec<_>>();
+
+        self.append(elements, added)
+    }
+
+    /// Edit a list without identities element by element, an element is taken
+    /// to be removed when the next new one is still further down the list.
+    fn update_positions(&mut self, elements: &[Node], old: &[Value], new: &[Value]) -> bool {
+        let mut next = 0;
+
+        for (i, element) in elements.iter().enumerate() {
+            let updated = match new.get(next) {
+                Some(new) if new == &old[i] => true,
+                Some(new) if !old[i + 1..].contains(new) => {
+                    self.update_element(element, &old[i], new)
+                }
+                _ => {
+                    if !self.remove_element(element) {
+                        return false;
+                    }
+                    continue;
+                }
+            };
+            if !updated {
+                return false;
+            }
+            next += 1;
+        }
+
+        self.append(elements, new[next..].to_vec())
+    }
+
+    /// Add elements after the last one of a list.
+    fn append(&mut self, elements: &[Node], added: Vec<Value>) -> bool {
+        if added.is_empty() {
+            return true;
+        }
+
+        let last = match elements.last() {
+            Some(last) => last,
+            None => return false,
+        };
+        let dash = match self.dash(last) {
+            Some(dash) => dash,
+            None => return false,
+        };
+
+        let line = self.end(last.start.line() - 1, last.end);
+        self.insert(line, &" ".repeat(dash), Value::Sequence(added));
+        true
+    }
+
+    fn update_entry(&mut self, entry: &Entry, old: &Value, new: &Value) {
+        let edits = self.edits.len();
+        if self.update(&entry.value, old, new) {
+            return;
+        }
+        self.edits.truncate(edits);
+
+        let mut replacement = Mapping::new();
+        replacement.insert(entry.key.clone().into(), new.clone());
+
+        let first = entry.mark.line() - 1;
+        self.replace(
+            first,
+            self.entry_end(entry),
+            entry.mark.col(),
+            Value::Mapping(replacement),
+        );
+    }
+
+    fn update_element(&mut self, element: &Node, old: &Value, new: &Value) -> bool {
+        let edits = self.edits.len();
+        if self.update(element, old, new) {
+            return true;
+        }
+        self.edits.truncate(edits);
+
+        let dash = match self.dash(element) {
+            Some(dash) => dash,
+            None => return false,
+        };
+
+        let first = element.start.line() - 1;
+        self.replace(
+            first,
+            self.end(first, element.end),
+            dash,
+            Value::Sequence(vec![new.clone()]),
+        );
+        true
+    }
+
+    fn remove_entry(&mut self, entry: &Entry) -> bool {
+        let first = entry.mark.line() - 1;
+        let last = self.entry_end(entry);
+
+        // the first key of a list element shares its line with the dash
+        if !self.lines[first][..byte(&self.lines[first], entry.mark.col())]
+            .trim()
+            .is_empty()
+        {
+            return false;
+        }
+
+        self.remove(first, last, entry.mark.col());
+        true
+    }
+
+    fn remove_element(&mut self, element: &Node) -> bool {
+        let dash = match self.dash(element) {
+            Some(dash) => dash,
+            None => return false,
+        };
+
+        let first = element.start.line() - 1;
+        self.remove(first, self.end(first, element.end), dash);
+        true
+    }
+
+    /// Remove lines along with the comments right above them.
+    fn remove(&mut self, mut first: usize, last: usize, indent: usize) {
+        while first > 0 {
+            let above = &self.lines[first - 1];
+            let comment = above.trim_start();
+            if !comment.starts_with('#') || above.len() - comment.len() < indent {
+                break;
+            }
+            first -= 1;
+        }
+
+        self.edits.push(Edit {
+            first,
+            last,
+            with: Vec::new(),
+        });
+    }
+
+    /// Replace lines with `value` written from column `indent`, keeping what's
+    /// before it on the first line and any comment after it if it's one line.
+    fn replace(&mut self, first: usize, last: usize, indent: usize, value: Value) {
+        let mut with = render(value, &" ".repeat(indent));
+        let line = &self.lines[first];
+        let prefix = &line[..byte(line, indent)];
+
+        with[0].replace_range(..indent, prefix);
+        if last == first + 1 && with.len() == 1 {
+            if let Some(comment) = comment(&line[prefix.len()..]) {
+                with[0].push_str(comment);
+            }
+        }
+
+        self.edits.push(Edit { first, last, with });
+    }
+
+    fn insert(&mut self, line: usize, indent: &str, value: Value) {
+        self.edits.push(Edit {
+            first: line,
+            last: line,
+            with: render(value, indent),
+        });
+    }
+
+    /// Column of the dash before a list element.
+    fn dash(&self, element: &Node) -> Option<usize> {
+        let line = &self.lines[element.start.line() - 1];
+        let before = &line[..byte(line, element.start.col())];
+        let dash = before.rfind('-')?;
+
+        before[dash + 1..]
+            .trim()
+            .is_empty()
+            .then(|| line[..dash].chars().count())
+    }
+
+    fn entry_end(&self, entry: &Entry) -> usize {
+        self.end(entry.mark.line() - 1, entry.value.end)
+    }
+
+    /// Line after a value starting on `first`, leaving out the blank lines and
+    /// comments between it and whatever is next.
+    fn end(&self, first: usize, end: Marker) -> usize {
+        let mut last = (end.line() - 1).max(first + 1);
+        while last > first + 1 {
+            let line = self.lines[last - 1].trim_start();
+            if !line.is_empty() && !line.starts_with('#') {
+                break;
+            }
+            last -= 1;
+        }
+        last
+    }
+}
+
+/// Hosts and listeners are matched up by their hostname or address, like interpolated values.
+fn identity(element: &Value) -> Option<&str> {
+    element
+        .get("hostname")
+        .or_else(|| element.get("bind"))?
+        .as_str()
+}
+
+fn identities(values: &[Value]) -> Option<Vec<&str>> {
+    values.iter().map(identity).collect()
+}
+
+/// Null and empty mappings are what leaving a key out means.
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Mapping(mapping) => mapping.values().all(is_empty),
+        _ => false,
+    }
+}
+
+/// `value` as yaml lines starting at `indent`, without the keys it doesn't need.
+fn render(mut value: Value, indent: &str) -> Vec<String> {
+    fn prune(value: &mut Value) {
+        match value {
+            Value::Mapping(mapping) => {
+                mapping.values_mut().for_each(prune);
+                mapping.retain(|_, value| !is_empty(value));
+            }
+            Value::Sequence(sequence) => sequence.iter_mut().for_each(prune),
+            _ => {}
+        }
+    }
+
+    match &mut value {
+        Value::Mapping(mapping) => mapping.values_mut().for_each(prune),
+        Value::Sequence(sequence) => sequence.iter_mut().for_each(prune),
+        _ => {}
+    }
+
+    serde_yaml::to_string(&value)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| format!("{}{}", indent, line))
+        .collect()
+}
+
+/// The comment at the end of a line, with the space before it.
+fn comment(line: &str) -> Option<&str> {
+    let mut quote = None;
+    let mut previous = ' ';
+
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '#' && previous.is_whitespace() => {
+                let start = line[..i].trim_end().len();
+                return Some(&line[start..]);
+            }
+            // quotes only start a string at the start of a value
+            None if (c == '"' || c == '\'')
+                && (previous.is_whitespace() || "{[,:".contains(previous)) =>
+            {
+                quote = Some(c)
+            }
+            None => {}
+        }
+        previous = c;
+    }
+
+    None
+}
+
+/// Byte offset of a column.
+fn byte(line: &str, column: usize) -> usize {
+    line.char_indices()
+        .nth(column)
+        .map_or(line.len(), |(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::validate;
+
+    static FIXTURE: &str = "\
+# router config
+version: 1
+defaulthost: lobby.example.com # where unknown hosts go
+
+virtualhosts:
+  # the lobby
+  - hostname: lobby.example.com
+    action:
+      forward: localhost:25566 # lobby server
+
+  - hostname: survival.example.com
+    action:
+      status:
+        static:
+          description: Survival
+      login:
+        forward: localhost:25567
+    versions:
+      - range: \"1.20+\"
+        action:
+          forward: localhost:25568
+      - range: \"1.19\"
+        action:
+          forward: localhost:25569
+
+timeouts:
+  connect: 5
+";
+
+    /// The fixture edited by `change`, which gets the fixture's values.
+    fn edited(change: impl FnOnce(&mut Value)) -> String {
+        let old: Value = serde_yaml::from_str(FIXTURE).unwrap();
+        let mut new = old.clone();
+        change(&mut new);
+
+        let edited = apply(FIXTURE, &old, &new).expect("fixture can be edited in place");
+        assert_eq!(serde_yaml::from_str::<Value>(&edited).unwrap(), new);
+        if let Err(invalid) = validate::parse(Path::new("config.yml"), &edited) {
+            panic!("edited config isn't valid:\n{}\n{}", invalid, edited);
+        }
+        edited
+    }
+
+    fn hosts(config: &mut Value) -> &mut Vec<Value> {
+        match &mut config["virtualhosts"] {
+            Value::Sequence(hosts) => hosts,
+            _ => unreachable!(),
+        }
+    }
+
+    fn yaml(source: &str) -> Value {
+        serde_yaml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn nothing_changed() {
+        assert_eq!(edited(|_| {}), FIXTURE);
+    }
+
+    #[test]
+    fn add_host() {
+        let edited = edited(|config| {
+            hosts(config).push(yaml(
+                "hostname: creative.example.com\naction:\n  forward: localhost:25570",
+            ))
+        });
+
+        assert_eq!(
+            edited,
+            FIXTURE.replace(
+                "          forward: localhost:25569\n",
+                "          forward: localhost:25569\n  \
+- hostname: creative.example.com\n    \
+action:\n      \
+forward: localhost:25570\n",
+            )
+        );
+    }
+
+    #[test]
+    fn remove_first_host() {
+        // and the default host with it, like the admin API does
+        let edited = edited(|config| {
+            hosts(config).remove(0);
+            config.as_mapping_mut().unwrap().remove("defaulthost");
+        });
+
+        // along with its comment and the blank line after it
+        assert_eq!(
+            edited,
+            FIXTURE
+                .replace(
+                    "defaulthost: lobby.example.com # where unknown hosts go\n",
+                    ""
+                )
+                .replace(
+                    "  # the lobby\n  \
+- hostname: lobby.example.com\n    \
+action:\n      \
+forward: localhost:25566 # lobby server\n\n",
+                    "",
+                )
+        );
+    }
+
+    #[test]
+    fn remove_last_host() {
+        let edited = edited(|config| {
+            hosts(config).remove(1);
+        });
+
+        let start = FIXTURE.find("  - hostname: survival").unwrap();
+        let end = FIXTURE.find("\ntimeouts:").unwrap();
+        assert_eq!(
+            edited,
+            format!("{}{}", &FIXTURE[..start], &FIXTURE[end + 1..])
+        );
+    }
+
+    #[test]
+    fn modify_host_keeps_comment() {
+        let edited = edited(|config| {
+            hosts(config)[0]["action"]["forward"] = "localhost:25599".into();
+        });
+
+        assert_eq!(
+            edited,
+            FIXTURE.replace(
+                "forward: localhost:25566 # lobby server",
+                "forward: localhost:25599 # lobby server",
+            )
+        );
+    }
+
+    #[test]
+    fn change_nested_action() {
+        let edited = edited(|config| {
+            hosts(config)[1]["action"]["status"] = yaml("forward: localhost:25571");
+        });
+
+        assert_eq!(
+            edited,
+            FIXTURE.replace(
+                "      status:\n        static:\n          description: Survival\n",
+                "      status:\n        forward: localhost:25571\n",
+            )
+        );
+    }
+
+    #[test]
+    fn edit_sequence_element() {
+        let edited = edited(|config| {
+            hosts(config)[1]["versions"][1]["range"] = "1.18-1.19".into();
+        });
+
+        assert_eq!(
+            edited,
+            FIXTURE.replace("- range: \"1.19\"\n", "- range: 1.18-1.19\n")
+        );
+    }
+
+    #[test]
+    fn remove_and_append_sequence_elements() {
+        let edited = edited(|config| match &mut hosts(config)[1]["versions"] {
+            Value::Sequence(versions) => {
+                versions.remove(0);
+                versions.push(yaml("range: \"1.8\"\naction:\n  forward: localhost:25572"));
+            }
+            _ => unreachable!(),
+        });
+
+        assert_eq!(
+            edited,
+            FIXTURE.replace(
+                "      - range: \"1.20+\"\n        \
+action:\n          \
+forward: localhost:25568\n      \
+- range: \"1.19\"\n        \
+action:\n          \
+forward: localhost:25569\n",
+                "      - range: \"1.19\"\n        \
+action:\n          \
+forward: localhost:25569\n      \
+- range: '1.8'\n        \
+action:\n          \
+forward: localhost:25572\n",
+            )
+        );
+    }
+
+    #[test]
+    fn top_level_keys() {
+        let edited = edited(|config| {
+            config["timeouts"]["idle"] = 300.into();
+            config["defaulthost"] = "survival.example.com".into();
+        });
+
+        assert_eq!(
+            edited,
+            FIXTURE
+                .replace(
+                    "defaulthost: lobby.example.com # where",
+                    "defaulthost: survival.example.com # where",
+                )
+                .replace("  connect: 5\n", "  connect: 5\n  idle: 300\n")
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! The file formats a config can be written in, picked by extension.
+
+use std::path::Path;
+
+use serde_yaml::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+/// Why a file couldn't be parsed, and the line and column it happened at.
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub message: String,
+    pub location: Option<(usize, usize)>,
+}
+
+impl Format {
+    /// Anything that isn't `.toml` or `.json` is read as yaml.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    pub fn is_config_file(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yml" | "yaml" | "toml" | "json")
+        )
+    }
+
+    pub fn parse(self, source: &str) -> Result<Value, SyntaxError> {
+        match self {
+            Format::Yaml => serde_yaml::from_str(source).map_err(|error| {
+                let location = error
+                    .location()
+                    .map(|location| (location.line(), location.column()));
+
+                // the location is already there
+                let message = error.to_string();
+                let message = match message.rsplit_once(" at line ") {
+                    Some((message, _)) => message.to_owned(),
+                    None => message,
+                };
+
+                SyntaxError { message, location }
+            }),
+            Format::Toml => toml::from_str(source).map_err(|error| {
+                let location = error.span().map(|span| line_column(source, span.start));
+
+                SyntaxError {
+                    message: error.message().trim().replace('\n', ", "),
+                    location,
+                }
+            }),
+            Format::Json => serde_json::from_str(source).map_err(|error| {
+                // the end of the file is column 0
+                let location = Some((error.line(), error.column().max(1)));
+
+                // the location is already there
+                let message = error.to_string();
+                let message = match message.rsplit_once(" at line ") {
+                    Some((message, _)) => message.to_owned(),
+                    None => message,
+                };
+
+                SyntaxError { message, location }
+            }),
+        }
+    }
+
+    pub fn write(self, mut value: Value) -> color_eyre::Result<String> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::to_string(&value)?,
+            Format::Toml => {
+                // toml has no null, leaving the key out means the same thing
+                remove_nulls(&mut value);
+                toml::to_string_pretty(&value)?
+            }
+            Format::Json => serde_json::to_string_pretty(&value)? + "\n",
+        })
+    }
+}
+
+/// 1-based line and column of a byte offset into `source`.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+
+    (line, column)
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Mapping(mapping) => {
+            mapping.retain(|_, value| !value.is_null());
+            mapping.values_mut().for_each(remove_nulls);
+        }
+        Value::Sequence(sequence) => sequence.iter_mut().for_each(remove_nulls),
+        Value::Tagged(tagged) => remove_nulls(&mut tagged.value),
+        _ => {}
+    }
+}
@@ -0,0 +1,335 @@
+//! `${VAR}`, `${env:VAR}`, `${VAR:-default}` and `${file:path}` in config
+//! values, with `$${` for a literal `${`. Defaults and paths can have their own
+//! `${...}` in them.
+//!
+//! Only string values are interpolated, before the config is deserialized, so
+//! they only work where a string is expected: `connect: ${TIMEOUT}` is still
+//! the string `"10"` when a number is needed. The original text is remembered
+//! so saving the config doesn't write out resolved secrets.
+
+use std::{collections::HashMap, env, fs, path::Path};
+
+use serde_yaml::Value;
+
+/// A string value as written in the file, and what it resolved to.
+#[derive(Debug, Clone)]
+pub struct Template {
+    raw: String,
+    resolved: String,
+}
+
+/// Interpolate every string in a yaml document, files are relative to `dir`.
+///
+/// Returns the templates by [`identity`] path, and any problems by the same
+/// positional paths serde_path_to_error uses.
+pub fn resolve(
+    value: &mut Value,
+    dir: &Path,
+) -> (HashMap<String, Template>, Vec<(String, String)>) {
+    let mut templates = HashMap::new();
+    let mut problems = Vec::new();
+    let lookup = |name: &str| env::var(name).ok();
+
+    walk(
+        value,
+        String::new(),
+        String::new(),
+        &|raw| interpolate(raw, dir, &lookup).unwrap_or_else(|_| raw.to_owned()),
+        &mut |path, id, value| {
+            if !value.contains('$') {
+                return;
+            }
+
+            match interpolate(value, dir, &lookup) {
+                Ok(resolved) => {
+                    if resolved != *value {
+                        let raw = std::mem::replace(value, resolved.clone());
+                        templates.insert(id.to_owned(), Template { raw, resolved });
+                    }
+                }
+                Err(message) => problems.push((path.to_owned(), message)),
+            }
+        },
+    );
+
+    (templates, problems)
+}
+
+/// Put the original text back for any value that hasn't been changed since it was resolved.
+pub fn restore(value: &mut Value, templates: &HashMap<String, Template>) {
+    if templates.is_empty() {
+        return;
+    }
+
+    walk(
+        value,
+        String::new(),
+        String::new(),
+        &str::to_owned,
+        &mut |_, id, value| {
+            if let Some(template) = templates.get(id) {
+                if template.resolved == *value {
+                    *value = template.raw.clone();
+                }
+            }
+        },
+    );
+}
+
+/// Call `visit` with the positional path, [`identity`] path and value of every string.
+fn walk(
+    value: &mut Value,
+    path: String,
+    id: String,
+    resolve: &dyn Fn(&str) -> String,
+    visit: &mut dyn FnMut(&str, &str, &mut String),
+) {
+    let join = |path: &str, key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+
+    match value {
+        Value::String(value) => visit(&path, &id, value),
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                if let Some(key) = key.as_str() {
+                    walk(value, join(&path, key), join(&id, key), resolve, visit);
+                }
+            }
+        }
+        Value::Sequence(sequence) => {
+            for (i, value) in sequence.iter_mut().enumerate() {
+                let index = format!("[{}]", i);
+                let element = identity(value, resolve).unwrap_or_else(|| index.clone());
+                walk(
+                    value,
+                    format!("{}{}", path, index),
+                    format!("{}{}", id, element),
+                    resolve,
+                    visit,
+                );
+            }
+        }
+        Value::Tagged(tagged) => walk(&mut tagged.value, path, id, resolve, visit),
+        _ => {}
+    }
+}
+
+/// Hosts and listeners are saved in whatever order they're stored in, so
+/// they're identified by their hostname or address rather than position.
+fn identity(element: &Value, resolve: &dyn Fn(&str) -> String) -> Option<String> {
+    let key = element
+        .get("hostname")
+        .or_else(|| element.get("bind"))?
+        .as_str()?;
+
+    Some(format!("[{}]", resolve(key)))
+}
+
+/// Looks up environment variables, [`env::var`] outside of tests.
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn interpolate(raw: &str, dir: &Path, lookup: Lookup<'_>) -> Result<String, String> {
+    let mut resolved = String::with_capacity(raw.len());
+    let mut rest = raw;
+
+    while let Some(start) = rest.find('$') {
+        resolved.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            resolved.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = closing_brace(after)
+                .ok_or_else(|| "${ is never closed, use $${ for a literal ${".to_owned())?;
+            resolved.push_str(&expand(&after[..end], dir, lookup)?);
+            rest = &after[end + 1..];
+        } else {
+            resolved.push('$');
+            rest = &rest[1..];
+        }
+    }
+    resolved.push_str(rest);
+
+    Ok(resolved)
+}
+
+/// Where the `}` ending an expression is, skipping over any nested in it.
+fn closing_brace(expression: &str) -> Option<usize> {
+    let bytes = expression.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i..] {
+            [b'$', b'$', b'{', ..] => i += 3,
+            [b'$', b'{', ..] => {
+                depth += 1;
+                i += 2;
+            }
+            [b'}', ..] if depth == 0 => return Some(i),
+            [b'}', ..] => {
+                depth -= 1;
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+fn expand(expression: &str, dir: &Path, lookup: Lookup<'_>) -> Result<String, String> {
+    // `:-` is a default rather than a source
+    let (source, rest) = match expression.split_once(':') {
+        Some((source, rest)) if !rest.starts_with('-') => (source, rest),
+        _ => ("env", expression),
+    };
+
+    match source {
+        "env" => variable(rest, dir, lookup),
+        "file" => {
+            let file = dir.join(interpolate(rest, dir, lookup)?);
+
+            // secrets are usually written with a trailing newline
+            fs::read_to_string(&file)
+                .map(|secret| secret.trim_end_matches(['\r', '\n']).to_owned())
+                .map_err(|err| format!("failed to read {}: {}", file.display(), err))
+        }
+        _ => Err(format!(
+            "unknown source {:?} in ${{{}}}, use env: or file:",
+            source, expression
+        )),
+    }
+}
+
+fn variable(expression: &str, dir: &Path, lookup: Lookup<'_>) -> Result<String, String> {
+    let (name, default) = match expression.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (expression, None),
+    };
+
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!(
+            "{:?} isn't a valid environment variable name",
+            name
+        ));
+    }
+
+    // like a shell, the default is used for empty variables too
+    match (lookup(name), default) {
+        (Some(value), Some(default)) if value.is_empty() => interpolate(default, dir, lookup),
+        (Some(value), _) => Ok(value),
+        (None, Some(default)) => interpolate(default, dir, lookup),
+        (None, None) => Err(format!("environment variable {} isn't set", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(raw: &str) -> Result<String, String> {
+        let variables = HashMap::from([
+            ("MC_ROUTER_TEST_HOST", "play.example.com"),
+            ("MC_ROUTER_TEST_EMPTY", ""),
+            ("MC_ROUTER_TEST_DIR", "mc_router_interpolate_nesting"),
+        ]);
+
+        interpolate(raw, &env::temp_dir(), &|name| {
+            variables.get(name).map(|value| value.to_string())
+        })
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            resolved("${MC_ROUTER_TEST_HOST}").unwrap(),
+            "play.example.com"
+        );
+        assert_eq!(
+            resolved("${env:MC_ROUTER_TEST_HOST}:25565").unwrap(),
+            "play.example.com:25565"
+        );
+        assert_eq!(resolved("${MC_ROUTER_TEST_UNSET:-lobby}").unwrap(), "lobby");
+        assert_eq!(resolved("${MC_ROUTER_TEST_EMPTY:-lobby}").unwrap(), "lobby");
+        assert_eq!(resolved("${MC_ROUTER_TEST_UNSET:-}").unwrap(), "");
+    }
+
+    #[test]
+    fn nesting() {
+        assert_eq!(
+            resolved("${MC_ROUTER_TEST_UNSET:-${MC_ROUTER_TEST_HOST}}").unwrap(),
+            "play.example.com"
+        );
+        assert_eq!(
+            resolved("${MC_ROUTER_TEST_UNSET:-${MC_ROUTER_TEST_UNSET:-lobby}}:1").unwrap(),
+            "lobby:1"
+        );
+        // only defaults that are used get resolved
+        assert_eq!(
+            resolved("${MC_ROUTER_TEST_HOST:-${MC_ROUTER_TEST_UNSET}}").unwrap(),
+            "play.example.com"
+        );
+        assert_eq!(
+            resolved("${MC_ROUTER_TEST_UNSET:-$${literal}}").unwrap(),
+            "${literal}"
+        );
+
+        let dir = env::temp_dir().join("mc_router_interpolate_nesting");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("secret"), "hunter2\n").unwrap();
+        assert_eq!(
+            resolved("${file:${MC_ROUTER_TEST_DIR}/secret}").unwrap(),
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            resolved("$${MC_ROUTER_TEST_HOST}").unwrap(),
+            "${MC_ROUTER_TEST_HOST}"
+        );
+        // `$$` on its own isn't an escape, existing values don't change
+        assert_eq!(resolved("pa$$word").unwrap(), "pa$$word");
+        assert_eq!(resolved("$5 $").unwrap(), "$5 $");
+        assert_eq!(
+            resolved("$$$${MC_ROUTER_TEST_HOST}").unwrap(),
+            "$$${MC_ROUTER_TEST_HOST}"
+        );
+    }
+
+    #[test]
+    fn missing_variables() {
+        let error = resolved("${MC_ROUTER_TEST_UNSET}").unwrap_err();
+        assert!(
+            error.contains("MC_ROUTER_TEST_UNSET isn't set"),
+            "{}",
+            error
+        );
+
+        let error = resolved("${MC_ROUTER_TEST_UNSET:-${MC_ROUTER_TEST_UNSET}}").unwrap_err();
+        assert!(error.contains("isn't set"), "{}", error);
+
+        let error = resolved("${file:mc_router_no_such_file}").unwrap_err();
+        assert!(error.starts_with("failed to read"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_sources_and_bad_syntax() {
+        let error = resolved("${vault:secret/router}").unwrap_err();
+        assert!(error.contains("unknown source \"vault\""), "{}", error);
+
+        assert!(resolved("${MC_ROUTER_TEST_HOST").is_err());
+        assert!(resolved("${MC_ROUTER_TEST_UNSET:-${MC_ROUTER_TEST_HOST}").is_err());
+        assert!(resolved("${not a name}").is_err());
+        assert!(resolved("${}").is_err());
+    }
+}
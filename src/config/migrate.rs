@@ -0,0 +1,284 @@
+//! Upgrading configs written for older versions of the router.
+//!
+//! Every config is saved with a `version`, when the shape of the config
+//! changes the version goes up and a migration is added here to turn the
+//! previous version into the new one.
+
+use serde_yaml::{Mapping, Value};
+
+/// Version of the configs this router reads and writes.
+pub const CURRENT_VERSION: u64 = 1;
+
+/// `MIGRATIONS[n]` turns a version `n` config into version `n + 1`.
+static MIGRATIONS: [fn(&mut Mapping); CURRENT_VERSION as usize] = [unversioned];
+
+/// Configs from before there was a version could list hosts under
+/// `forwards` too, and said where hosts went with a `target` rather than an
+/// `action`.
+fn unversioned(config: &mut Mapping) {
+    if let Some(Value::Sequence(hosts)) = config.get_mut("virtualhosts") {
+        hosts.iter_mut().for_each(target_to_action);
+    }
+
+    let mut forwards = match config.remove("forwards") {
+        Some(Value::Sequence(forwards)) => forwards,
+        // serde will say it's not a known key
+        Some(forwards) => {
+            config.insert("forwards".into(), forwards);
+            return;
+        }
+        None => return,
+    };
+    forwards.iter_mut().for_each(target_to_action);
+
+    match config.get_mut("virtualhosts") {
+        Some(Value::Sequence(hosts)) => hosts.extend(forwards),
+        Some(_) => {
+            config.insert("forwards".into(), forwards.into());
+        }
+        None => {
+            config.insert("virtualhosts".into(), forwards.into());
+        }
+    }
+}
+
+/// Replace a host's `target` with the `action` it means, where it was.
+fn target_to_action(host: &mut Value) {
+    let host = match host {
+        Value::Mapping(host) if !host.contains_key("action") => host,
+        _ => return,
+    };
+    let action = match host.get("target").and_then(target_action) {
+        Some(action) => action,
+        None => return,
+    };
+
+    let mut action = Some(action);
+    *host = std::mem::take(host)
+        .into_iter()
+        .map(|(key, value)| match key.as_str() {
+            Some("target") => ("action".into(), action.take().unwrap_or(value)),
+            _ => (key, value),
+        })
+        .collect();
+}
+
+/// `address`, `Forward: address` or `Status: {online_players, max_players,
+/// description}`, the variants written either as a key or a yaml tag.
+fn target_action(target: &Value) -> Option<Value> {
+    let (variant, value) = match target {
+        Value::String(_) => ("forward".to_owned(), target),
+        Value::Tagged(tagged) => (
+            tagged.tag.to_string().trim_start_matches('!').to_owned(),
+            &tagged.value,
+        ),
+        Value::Mapping(target) if target.len() == 1 => {
+            let (variant, value) = target.iter().next()?;
+            (variant.as_str()?.to_owned(), value)
+        }
+        _ => return None,
+    };
+
+    let (key, value) = match variant.to_ascii_lowercase().as_str() {
+        "forward" => ("forward", value.clone()),
+        "status" => {
+            let status = value
+                .as_mapping()?
+                .iter()
+                .map(|(key, value)| match key.as_str() {
+                    Some("online_players") => ("cur_players".into(), value.clone()),
+                    _ => (key.clone(), value.clone()),
+                })
+                .collect();
+            ("static", Value::Mapping(status))
+        }
+        _ => return None,
+    };
+
+    let mut action = Mapping::new();
+    action.insert(key.into(), value);
+    Some(Value::Mapping(action))
+}
+
+/// Bring a config up to the current version, taking its `version` key out.
+///
+/// Returns the version the config was written for if it had to be migrated.
+pub fn migrate(config: &mut Value) -> Result<Option<u64>, String> {
+    let config = match config {
+        Value::Mapping(config) => config,
+        // serde will have something to say about it
+        _ => return Ok(None),
+    };
+
+    let version = match config.remove("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| "version must be a whole number".to_owned())?,
+    };
+
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "config is for version {} but this router only understands up to version {}, upgrade the router to use it",
+            version, CURRENT_VERSION
+        ));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(config);
+    }
+
+    Ok((version < CURRENT_VERSION).then_some(version))
+}
+
+/// Put the current version first in a config about to be saved.
+pub fn stamp(config: &mut Value) {
+    if let Value::Mapping(mapping) = config {
+        let mut stamped = Mapping::with_capacity(mapping.len() + 1);
+        stamped.insert("version".into(), CURRENT_VERSION.into());
+        stamped.extend(std::mem::take(mapping));
+        *mapping = stamped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated(before: &str, after: &str) {
+        let mut config: Value = serde_yaml::from_str(before).unwrap();
+        let after: Value = serde_yaml::from_str(after).unwrap();
+
+        assert_eq!(migrate(&mut config), Ok(Some(0)));
+        assert_eq!(
+            serde_yaml::to_string(&config).unwrap(),
+            serde_yaml::to_string(&after).unwrap()
+        );
+    }
+
+    #[test]
+    fn forwards_become_virtual_hosts() {
+        migrated(
+            "\
+defaulthost: lobby.example.com
+virtualhosts:
+  - hostname: lobby.example.com
+    action:
+      forward: localhost:25566
+forwards:
+  - hostname: survival.example.com
+    target: localhost:25567
+  - hostname: creative.example.com
+    target: localhost:25568
+",
+            "\
+defaulthost: lobby.example.com
+virtualhosts:
+  - hostname: lobby.example.com
+    action:
+      forward: localhost:25566
+  - hostname: survival.example.com
+    action:
+      forward: localhost:25567
+  - hostname: creative.example.com
+    action:
+      forward: localhost:25568
+",
+        );
+    }
+
+    #[test]
+    fn forwards_without_virtual_hosts() {
+        migrated(
+            "\
+forwards:
+  - hostname: survival.example.com
+    target: localhost:25567
+",
+            "\
+virtualhosts:
+  - hostname: survival.example.com
+    action:
+      forward: localhost:25567
+",
+        );
+    }
+
+    #[test]
+    fn forward_targets_become_actions() {
+        migrated(
+            "\
+virtualhosts:
+  - hostname: a.example.com
+    target:
+      Forward: localhost:25566
+  - hostname: b.example.com
+    target: !Forward localhost:25567
+    supported: 1.20+
+",
+            "\
+virtualhosts:
+  - hostname: a.example.com
+    action:
+      forward: localhost:25566
+  - hostname: b.example.com
+    action:
+      forward: localhost:25567
+    supported: 1.20+
+",
+        );
+    }
+
+    #[test]
+    fn status_targets_become_static_actions() {
+        migrated(
+            "\
+virtualhosts:
+  - hostname: a.example.com
+    target:
+      Status:
+        online_players: 3
+        max_players: 20
+        description: Back soon
+  - hostname: b.example.com
+    target: !Status
+      online_players: 0
+      max_players: 10
+      description: Closed
+",
+            "\
+virtualhosts:
+  - hostname: a.example.com
+    action:
+      static:
+        cur_players: 3
+        max_players: 20
+        description: Back soon
+  - hostname: b.example.com
+    action:
+      static:
+        cur_players: 0
+        max_players: 10
+        description: Closed
+",
+        );
+    }
+
+    #[test]
+    fn current_configs_are_left_alone() {
+        let source = "\
+version: 1
+virtualhosts:
+  - hostname: a.example.com
+    action:
+      forward: localhost:25566
+    target: left for serde to complain about
+";
+        let mut config: Value = serde_yaml::from_str(source).unwrap();
+        let mut expected = config.clone();
+        expected.as_mapping_mut().unwrap().remove("version");
+
+        assert_eq!(migrate(&mut config), Ok(None));
+        assert_eq!(config, expected);
+    }
+}
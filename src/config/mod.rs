@@ -1,35 +1,115 @@
+mod edit;
+mod format;
 mod hostname;
+mod interpolate;
+mod migrate;
+mod protocol;
 mod serveraddr;
+mod validate;
 
 pub use hostname::Hostname;
+pub use migrate::CURRENT_VERSION;
+pub use protocol::ProtocolRange;
 pub use serveraddr::ServerAddr;
+pub use validate::is_hosts_file;
 
+use format::Format;
+use interpolate::Template;
+
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io,
+    convert::TryFrom,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
 };
+use tracing::warn;
+
+pub static DEFAULT_CONFIG_PATH: &str = "config.yml";
+static DEFAULT_CONFIG_PATHS: [&str; 4] =
+    ["config.yml", "config.yaml", "config.toml", "config.json"];
 
-static CONFIG_PATH: &str = "config.yml";
+lazy_static! {
+    static ref CONFIG_PATH: RwLock<PathBuf> = RwLock::new(PathBuf::from(DEFAULT_CONFIG_PATH));
+}
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(rename = "defaulthost")]
     default_host: Option<Hostname>,
-    #[serde(rename = "virtualhosts", with = "hosts_serde")]
-    pub hosts: HashMap<Hostname, VirtualHost>,
+    /// Files, or directories of `.yml` files, each with their own
+    /// `virtualhosts`. Relative paths start from the config file's directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    /// Hosts here include the ones from included files.
+    #[serde(rename = "virtualhosts", with = "hosts_serde", default)]
+    pub hosts: IndexMap<Hostname, VirtualHost>,
+    /// Which file each included host came from, they're never saved here.
+    #[serde(skip)]
+    included: HashMap<Hostname, PathBuf>,
+    /// Interpolated values as they were written, so they're saved that way too.
+    #[serde(skip)]
+    templates: HashMap<String, Template>,
+    /// Version the file was written for, when it had to be migrated to the current one.
+    #[serde(skip)]
+    migrated_from: Option<u64>,
+    /// The file as it was last read or written, so saving only changes what was edited.
+    #[serde(skip)]
+    saved: Option<Saved>,
+    /// Every script the config runs, compiled when it was read.
+    #[cfg(feature = "scripting")]
+    #[serde(skip)]
+    pub(crate) scripts: crate::scripting::Scripts,
+    #[serde(rename = "ratelimit", default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<Listener>,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    /// Unix socket used to pass listeners to a newer router when upgrading.
+    #[serde(rename = "handoffsocket")]
+    pub handoff_socket: Option<PathBuf>,
+    /// Unix socket accepting cli commands, used by `mc_router ctl`.
+    #[serde(rename = "controlsocket")]
+    pub control_socket: Option<PathBuf>,
+    /// Kick message for logins while the router is in maintenance.
+    pub maintenance: Option<String>,
+    /// Address to serve the admin API on, disabled when unset.
+    #[serde(rename = "adminbind")]
+    pub admin_bind: Option<SocketAddr>,
+    /// Bearer token the admin API requires, needed for it to listen anywhere
+    /// but loopback. Best set with `${file:...}` or `${VAR}`.
+    #[serde(rename = "admintoken")]
+    pub admin_token: Option<String>,
+    /// Address to serve Prometheus metrics on, disabled when unset.
+    #[serde(rename = "metricsbind")]
+    pub metrics_bind: Option<SocketAddr>,
+    /// Script asked about every connection before the virtual hosts are, see
+    /// [`Action::Script`]. Connections it doesn't route go to their host as usual.
+    pub script: Option<PathBuf>,
 }
 
-mod hosts_serde {
-    use std::collections::HashMap;
+#[derive(Debug, Clone)]
+struct Saved {
+    source: String,
+    value: serde_yaml::Value,
+}
 
+mod hosts_serde {
+    use indexmap::IndexMap;
     use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serializer};
 
     use super::{Hostname, VirtualHost};
 
     pub fn serialize<S>(
-        hosts: &HashMap<Hostname, VirtualHost>,
+        hosts: &IndexMap<Hostname, VirtualHost>,
         serializer: S,
     ) -> Result<S::Ok, S::Error>
     where
@@ -44,11 +124,11 @@ mod hosts_serde {
         seq.end()
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<HashMap<Hostname, VirtualHost>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<IndexMap<Hostname, VirtualHost>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let mut hosts = HashMap::new();
+        let mut hosts = IndexMap::new();
 
         Vec::<VirtualHost>::deserialize(deserializer)?
             .into_iter()
@@ -61,14 +141,126 @@ mod hosts_serde {
 }
 
 impl Config {
+    /// Record that `saved`, a copy of this config, has been written to the file.
+    pub fn mark_saved(&mut self, saved: &Config) {
+        self.saved = saved.saved.clone();
+        self.migrated_from = saved.migrated_from;
+    }
+
+    /// Virtual hosts with interpolated values as they were written rather
+    /// than what they resolved to, so secrets aren't shown.
+    pub fn hosts_as_written(&self) -> color_eyre::Result<Vec<serde_yaml::Value>> {
+        let mut value = serde_yaml::to_value(self)?;
+        interpolate::restore(&mut value, &self.templates);
+
+        Ok(match value.get_mut("virtualhosts") {
+            Some(serde_yaml::Value::Sequence(hosts)) => std::mem::take(hosts),
+            _ => Vec::new(),
+        })
+    }
+
+    pub fn default_host(&self) -> Option<&Hostname> {
+        self.default_host.as_ref()
+    }
+
+    pub fn set_default_host(&mut self, hostname: Option<Hostname>) {
+        self.default_host = hostname;
+    }
+
+    pub fn migrated_from(&self) -> Option<u64> {
+        self.migrated_from
+    }
+
+    /// Included file the host was read from, if it wasn't from the main config.
+    pub fn included_from(&self, hostname: &Hostname) -> Option<&Path> {
+        self.included.get(hostname).map(PathBuf::as_path)
+    }
+
+    /// Where each `include` points, given the path the config was read from.
+    pub fn include_paths(&self, file: &Path) -> Vec<PathBuf> {
+        self.include
+            .iter()
+            .map(|include| relative_to(file, include))
+            .collect()
+    }
+
+    /// Every script the config runs, relative ones resolved from `file`.
+    pub fn script_paths(&self, file: &Path) -> Vec<PathBuf> {
+        let hosts = self.hosts.values().chain(
+            self.listeners
+                .iter()
+                .flat_map(|listener| listener.hosts.values()),
+        );
+        let host_scripts = hosts.flat_map(|host| {
+            validate::host_scripts("", host)
+                .into_iter()
+                .map(|(_, script)| script)
+        });
+
+        self.script
+            .as_deref()
+            .into_iter()
+            .chain(host_scripts)
+            .map(|script| relative_to(file, script))
+            .collect()
+    }
+
     pub fn get_default_host(&self) -> Option<&VirtualHost> {
         self.default_host
             .as_ref()
             .and_then(|hostname| self.hosts.get(hostname))
     }
+
+    pub fn get_listener(&self, bind: &SocketAddr) -> Option<&Listener> {
+        self.listeners
+            .iter()
+            .find(|listener| &listener.bind == bind)
+    }
+
+    /// Find the host for a connection made to `listener`, falling back to the
+    /// default host.
+    ///
+    /// Listeners with their own virtual hosts only ever see those, otherwise they
+    /// share the top level hosts but may still pick a different default.
+    pub fn find_host(&self, listener: &SocketAddr, hostname: &Hostname) -> Option<&VirtualHost> {
+        let listener = match self.get_listener(listener) {
+            Some(listener) => listener,
+            None => return self.hosts.get(hostname).or_else(|| self.get_default_host()),
+        };
+
+        let hosts = if listener.hosts.is_empty() {
+            &self.hosts
+        } else {
+            &listener.hosts
+        };
+
+        let default_host = match &listener.default_host {
+            Some(default_host) => hosts.get(default_host),
+            None if listener.hosts.is_empty() => self.get_default_host(),
+            None => None,
+        };
+
+        hosts.get(hostname).or(default_host)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub bind: SocketAddr,
+    #[serde(rename = "defaulthost")]
+    default_host: Option<Hostname>,
+    #[serde(
+        rename = "virtualhosts",
+        with = "hosts_serde",
+        default,
+        skip_serializing_if = "IndexMap::is_empty"
+    )]
+    pub hosts: IndexMap<Hostname, VirtualHost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 // TODO: allow multiple targets and try them by priority/round robin
 // e.g.
 // prority:
@@ -82,11 +274,43 @@ impl Config {
 // load balance between the targets
 pub struct VirtualHost {
     pub hostname: Hostname,
+    /// Used when none of the version rules match the client.
     pub action: Action,
+    /// Actions for clients on particular versions, the first that matches is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<VersionRule>,
+    /// Versions clients need to be on, others see the server as outdated and
+    /// are kicked when they log in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supported: Option<ProtocolRange>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
+impl VirtualHost {
+    /// The action for a client speaking `protocol_version`.
+    pub fn action_for(&self, protocol_version: i32) -> &Action {
+        self.versions
+            .iter()
+            .find(|rule| rule.range.contains(protocol_version))
+            .map_or(&self.action, |rule| &rule.action)
+    }
+
+    /// Whether clients speaking `protocol_version` can use this host.
+    pub fn supports(&self, protocol_version: i32) -> bool {
+        self.supported
+            .as_ref()
+            .is_none_or(|supported| supported.contains(protocol_version))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VersionRule {
+    pub range: ProtocolRange,
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged, try_from = "ActionKeys")]
 pub enum Action {
     Conditional {
         status: StatusAction,
@@ -99,6 +323,15 @@ pub enum Action {
     Forward {
         forward: ForwardAction,
     },
+    /// A [`crate::CustomAction`] registered under this name.
+    Custom {
+        custom: String,
+    },
+    /// A rhai script that works out where each connection goes, relative to
+    /// the config file's directory. Needs the `scripting` feature.
+    Script {
+        script: PathBuf,
+    },
 }
 
 impl Action {
@@ -112,6 +345,12 @@ impl Action {
             Action::Forward { forward } => StatusAction::Forward {
                 forward: forward.clone(),
             },
+            Action::Custom { custom } => StatusAction::Custom {
+                custom: custom.clone(),
+            },
+            Action::Script { script } => StatusAction::Script {
+                script: script.clone(),
+            },
         }
     }
 
@@ -125,27 +364,142 @@ impl Action {
             Action::Forward { forward } => LoginAction::Forward {
                 forward: forward.clone(),
             },
+            Action::Custom { custom } => LoginAction::Custom {
+                custom: custom.clone(),
+            },
+            Action::Script { script } => LoginAction::Script {
+                script: script.clone(),
+            },
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged, try_from = "TargetKeys")]
 pub enum StatusAction {
     Static { r#static: StaticAction },
     Forward { forward: ForwardAction },
+    Custom { custom: String },
+    Script { script: PathBuf },
     // Modify { modify: ModifyAction },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged, try_from = "TargetKeys")]
 pub enum LoginAction {
     Static { r#static: StaticAction },
     Forward { forward: ForwardAction },
+    Custom { custom: String },
+    Script { script: PathBuf },
+}
+
+// untagged enums only say the data "did not match any variant", reading every
+// key an action can have first means typos and missing keys are reported properly
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActionKeys {
+    status: Option<StatusAction>,
+    login: Option<LoginAction>,
+    r#static: Option<StaticAction>,
+    forward: Option<ForwardAction>,
+    custom: Option<String>,
+    script: Option<PathBuf>,
+}
+
+impl TryFrom<ActionKeys> for Action {
+    type Error = &'static str;
+
+    fn try_from(keys: ActionKeys) -> Result<Self, Self::Error> {
+        match keys {
+            ActionKeys {
+                status: Some(status),
+                login: Some(login),
+                r#static: None,
+                forward: None,
+                custom: None,
+                script: None,
+            } => Ok(Action::Conditional { status, login }),
+            ActionKeys {
+                status: None,
+                login: None,
+                r#static: Some(r#static),
+                forward: None,
+                custom: None,
+                script: None,
+            } => Ok(Action::Static { r#static }),
+            ActionKeys {
+                status: None,
+                login: None,
+                r#static: None,
+                forward: Some(forward),
+                custom: None,
+                script: None,
+            } => Ok(Action::Forward { forward }),
+            ActionKeys {
+                status: None,
+                login: None,
+                r#static: None,
+                forward: None,
+                custom: Some(custom),
+                script: None,
+            } => Ok(Action::Custom { custom }),
+            ActionKeys {
+                status: None,
+                login: None,
+                r#static: None,
+                forward: None,
+                custom: None,
+                script: Some(script),
+            } => Ok(Action::Script { script }),
+
+            _ => Err(
+                "action needs exactly one of forward, static, custom or script, or both status and login",
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetKeys {
+    r#static: Option<StaticAction>,
+    forward: Option<ForwardAction>,
+    custom: Option<String>,
+    script: Option<PathBuf>,
+}
+
+impl TryFrom<TargetKeys> for StatusAction {
+    type Error = &'static str;
+
+    fn try_from(keys: TargetKeys) -> Result<Self, Self::Error> {
+        match (keys.r#static, keys.forward, keys.custom, keys.script) {
+            (Some(r#static), None, None, None) => Ok(StatusAction::Static { r#static }),
+            (None, Some(forward), None, None) => Ok(StatusAction::Forward { forward }),
+            (None, None, Some(custom), None) => Ok(StatusAction::Custom { custom }),
+            (None, None, None, Some(script)) => Ok(StatusAction::Script { script }),
+            _ => Err("status needs exactly one of forward, static, custom or script"),
+        }
+    }
+}
+
+impl TryFrom<TargetKeys> for LoginAction {
+    type Error = &'static str;
+
+    fn try_from(keys: TargetKeys) -> Result<Self, Self::Error> {
+        match (keys.r#static, keys.forward, keys.custom, keys.script) {
+            (Some(r#static), None, None, None) => Ok(LoginAction::Static { r#static }),
+            (None, Some(forward), None, None) => Ok(LoginAction::Forward { forward }),
+            (None, None, Some(custom), None) => Ok(LoginAction::Custom { custom }),
+            (None, None, None, Some(script)) => Ok(LoginAction::Script { script }),
+            _ => Err("login needs exactly one of forward, static, custom or script"),
+        }
+    }
 }
 
 // TODO: flesh this out, there's many more fields the status can contain (or just allow a raw json object?)
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct StaticAction {
     pub version_name: Option<String>,
     pub protocol_version: Option<i32>,
@@ -156,24 +510,148 @@ pub struct StaticAction {
     pub kick_message: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ForwardAction(pub ServerAddr);
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Maximum number of connections open at once, unlimited when unset.
+    #[serde(rename = "maxconnections")]
+    pub max_connections: Option<usize>,
+    /// Prefix length used to group IPv4 clients into subnets.
+    #[serde(rename = "ipv4prefix")]
+    pub ipv4_prefix: Option<u8>,
+    /// Prefix length used to group IPv6 clients into subnets.
+    #[serde(rename = "ipv6prefix")]
+    pub ipv6_prefix: Option<u8>,
+
+    #[serde(default)]
+    pub status: RateLimitBuckets,
+    #[serde(default)]
+    pub login: RateLimitBuckets,
+
+    #[serde(rename = "fullmessage")]
+    pub full_message: Option<String>,
+    #[serde(rename = "throttlemessage")]
+    pub throttle_message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitBuckets {
+    #[serde(rename = "perip")]
+    pub per_ip: Option<TokenBucket>,
+    #[serde(rename = "persubnet")]
+    pub per_subnet: Option<TokenBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct TokenBucket {
+    /// Tokens added per second.
+    pub rate: f64,
+    /// Maximum tokens the bucket can hold.
+    pub burst: f64,
+}
+
+static DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+static DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+
+/// All timeouts are in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// Time a client has to get through the handshake and status request or login start.
+    handshake: Option<u64>,
+    /// Time allowed for connecting to a forward target.
+    connect: Option<u64>,
+    /// Time a proxied session can go without receiving any data, disabled when unset.
+    idle: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT))
+    }
+
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect.unwrap_or(DEFAULT_CONNECT_TIMEOUT))
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle.map(Duration::from_secs)
+    }
+}
+
+static DEFAULT_SHUTDOWN_DEADLINE: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Shutdown {
+    /// Seconds to wait for proxied sessions to end before cutting them.
+    deadline: Option<u64>,
+    /// When set, listeners keep accepting while draining and kick logins with this.
+    #[serde(rename = "kickmessage")]
+    pub kick_message: Option<String>,
+}
+
+impl Shutdown {
+    pub fn deadline(&self) -> Duration {
+        Duration::from_secs(self.deadline.unwrap_or(DEFAULT_SHUTDOWN_DEADLINE))
+    }
+}
+
 // // todo big work
 // #[derive(Serialize, Deserialize, Debug, Clone)]
 // pub struct ModifyAction {}
 
+/// The first of `config.yml`, `config.yaml`, `config.toml` or `config.json`
+/// that exists, a new config is created as `config.yml`.
+pub fn default_path() -> PathBuf {
+    DEFAULT_CONFIG_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// Use a different config file for every load and save from now on.
+pub fn set_path(path: PathBuf) {
+    *CONFIG_PATH.write().unwrap() = path;
+}
+
+pub fn path() -> PathBuf {
+    CONFIG_PATH.read().unwrap().clone()
+}
+
+/// Resolve a path written in the config file at `file`, relative ones start
+/// from its directory.
+pub(crate) fn relative_to(file: &Path, path: &Path) -> PathBuf {
+    file.parent().unwrap_or_else(|| Path::new("")).join(path)
+}
+
+pub fn exists() -> bool {
+    path().exists()
+}
+
 pub fn load() -> color_eyre::Result<Config> {
-    let file = File::open(CONFIG_PATH);
+    let path = path();
+    let source = fs::read_to_string(&path);
 
-    if let Ok(file) = file {
-        Ok(serde_yaml::from_reader(file)?)
+    if let Ok(source) = source {
+        let mut config = validate::parse(&path, &source)?;
+        config.saved = Some(Saved {
+            value: to_value(&config)?,
+            source,
+        });
+        Ok(config)
     } else {
-        Ok(match file.unwrap_err() {
+        Ok(match source.unwrap_err() {
             err if err.kind() == io::ErrorKind::NotFound => {
-                let config = Default::default();
+                let mut config = Default::default();
 
-                save(&config)?;
+                save(&mut config)?;
                 Ok(config)
             }
             other => Err(other),
@@ -181,7 +659,83 @@ pub fn load() -> color_eyre::Result<Config> {
     }
 }
 
-pub fn save(config: &Config) -> color_eyre::Result<()> {
-    fs::write(CONFIG_PATH, serde_yaml::to_string(config)?)?;
+/// Write the config back to its file, only changing what's different from
+/// when it was read so comments and ordering are kept.
+///
+/// Only yaml can be edited like this, other formats are written out again.
+pub fn save(config: &mut Config) -> color_eyre::Result<()> {
+    let path = path();
+
+    // keep what was there before it's replaced with the migrated config, the
+    // first backup is kept as that's the one written by the older router
+    if let Some(version) = config.migrated_from {
+        let mut backup = path.clone().into_os_string();
+        backup.push(format!(".v{}.bak", version));
+
+        if path.exists() && !Path::new(&backup).exists() {
+            fs::copy(&path, &backup)?;
+        }
+    }
+
+    let value = to_value(config)?;
+    let format = Format::of(&path);
+
+    let edited = match &config.saved {
+        Some(saved) if format == Format::Yaml && config.migrated_from.is_none() => {
+            // anything the edit got wrong would read back differently
+            let edited = edit::apply(&saved.source, &saved.value, &value).filter(|source| {
+                validate::parse(&path, source)
+                    .ok()
+                    .and_then(|edited| to_value(&edited).ok())
+                    .as_ref()
+                    == Some(&value)
+            });
+
+            if edited.is_none() {
+                warn!(
+                    "Couldn't edit {} in place, writing the whole config instead",
+                    path.display()
+                );
+            }
+            edited
+        }
+        _ => None,
+    };
+
+    let source = match edited {
+        Some(source) => source,
+        None => format.write(value.clone())?,
+    };
+    fs::write(&path, &source)?;
+
+    config.migrated_from = None;
+    config.saved = Some(Saved { source, value });
+    Ok(())
+}
+
+/// Write a config to any file, in the format its extension says.
+pub fn save_to(config: &Config, path: &Path) -> color_eyre::Result<()> {
+    fs::write(path, Format::of(path).write(to_value(config)?)?)?;
     Ok(())
 }
+
+/// The config as it's saved.
+fn to_value(config: &Config) -> color_eyre::Result<serde_yaml::Value> {
+    let mut value = serde_yaml::to_value(config)?;
+
+    // included hosts are owned by whatever wrote their files
+    if let Some(serde_yaml::Value::Sequence(hosts)) = value.get_mut("virtualhosts") {
+        hosts.retain(|host| {
+            let hostname = host.get("hostname").and_then(serde_yaml::Value::as_str);
+            !config
+                .included
+                .keys()
+                .any(|included| Some(included.0.as_str()) == hostname)
+        });
+    }
+
+    interpolate::restore(&mut value, &config.templates);
+    migrate::stamp(&mut value);
+
+    Ok(value)
+}
@@ -0,0 +1,311 @@
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Serialize,
+};
+use std::{fmt, str::FromStr};
+
+/// Release versions and the protocol they speak, oldest first.
+static VERSIONS: &[(&str, i32)] = &[
+    ("1.7.2", 4),
+    ("1.7.3", 4),
+    ("1.7.4", 4),
+    ("1.7.5", 4),
+    ("1.7.6", 5),
+    ("1.7.7", 5),
+    ("1.7.8", 5),
+    ("1.7.9", 5),
+    ("1.7.10", 5),
+    ("1.8", 47),
+    ("1.8.1", 47),
+    ("1.8.2", 47),
+    ("1.8.3", 47),
+    ("1.8.4", 47),
+    ("1.8.5", 47),
+    ("1.8.6", 47),
+    ("1.8.7", 47),
+    ("1.8.8", 47),
+    ("1.8.9", 47),
+    ("1.9", 107),
+    ("1.9.1", 108),
+    ("1.9.2", 109),
+    ("1.9.3", 110),
+    ("1.9.4", 110),
+    ("1.10", 210),
+    ("1.10.1", 210),
+    ("1.10.2", 210),
+    ("1.11", 315),
+    ("1.11.1", 316),
+    ("1.11.2", 316),
+    ("1.12", 335),
+    ("1.12.1", 338),
+    ("1.12.2", 340),
+    ("1.13", 393),
+    ("1.13.1", 401),
+    ("1.13.2", 404),
+    ("1.14", 477),
+    ("1.14.1", 480),
+    ("1.14.2", 485),
+    ("1.14.3", 490),
+    ("1.14.4", 498),
+    ("1.15", 573),
+    ("1.15.1", 575),
+    ("1.15.2", 578),
+    ("1.16", 735),
+    ("1.16.1", 736),
+    ("1.16.2", 751),
+    ("1.16.3", 753),
+    ("1.16.4", 754),
+    ("1.16.5", 754),
+    ("1.17", 755),
+    ("1.17.1", 756),
+    ("1.18", 757),
+    ("1.18.1", 757),
+    ("1.18.2", 758),
+    ("1.19", 759),
+    ("1.19.1", 760),
+    ("1.19.2", 760),
+    ("1.19.3", 761),
+    ("1.19.4", 762),
+    ("1.20", 763),
+    ("1.20.1", 763),
+    ("1.20.2", 764),
+    ("1.20.3", 765),
+    ("1.20.4", 765),
+    ("1.20.5", 766),
+    ("1.20.6", 766),
+    ("1.21", 767),
+    ("1.21.1", 767),
+    ("1.21.2", 768),
+    ("1.21.3", 768),
+    ("1.21.4", 769),
+    ("1.21.5", 770),
+    ("1.21.6", 771),
+    ("1.21.7", 772),
+    ("1.21.8", 772),
+    ("1.21.9", 773),
+    ("1.21.10", 773),
+    ("1.21.11", 774),
+];
+
+/// Protocol versions a rule applies to, written with release versions or
+/// protocol numbers: `1.8-1.12.2`, `1.20.5+`, `1.16` or `47-340`.
+///
+/// A release without a patch version covers all of them, so `1.8-1.12` goes
+/// up to 1.12.2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolRange {
+    written: String,
+    min: i32,
+    max: Option<i32>,
+}
+
+impl ProtocolRange {
+    pub fn contains(&self, protocol_version: i32) -> bool {
+        protocol_version >= self.min && self.max.is_none_or(|max| protocol_version <= max)
+    }
+
+    /// The protocol to tell a client outside the range about, the closest
+    /// end so it's told whether it's the client or server that's outdated.
+    pub fn nearest(&self, protocol_version: i32) -> i32 {
+        match self.max {
+            Some(max) if protocol_version > max => max,
+            _ => self.min,
+        }
+    }
+
+    /// The range as releases, like `1.20–1.21.1` or `1.20.5+`.
+    pub fn releases(&self) -> String {
+        let first = VERSIONS
+            .iter()
+            .find(|(_, protocol)| *protocol == self.min)
+            .map_or_else(
+                || format!("protocol {}", self.min),
+                |(name, _)| name.to_string(),
+            );
+        let last = self.max.map(|max| {
+            VERSIONS
+                .iter()
+                .rev()
+                .find(|(_, protocol)| *protocol == max)
+                .map_or_else(|| format!("protocol {}", max), |(name, _)| name.to_string())
+        });
+
+        match last {
+            None => format!("{}+", first),
+            Some(last) if last == first => first,
+            Some(last) => format!("{}–{}", first, last),
+        }
+    }
+}
+
+/// Protocols of the releases `version` names, e.g. 1.12 is 335 to 340.
+fn release(version: &str) -> Result<(i32, i32), String> {
+    if let Ok(protocol) = version.parse::<i32>() {
+        return Ok((protocol, protocol));
+    }
+
+    let patch_prefix = format!("{}.", version);
+    let mut protocols = VERSIONS
+        .iter()
+        .filter(|(name, _)| *name == version || name.starts_with(&patch_prefix))
+        .map(|(_, protocol)| *protocol);
+
+    let min = protocols.next().ok_or_else(|| {
+        format!(
+            "unknown version {:?}, use its protocol number instead",
+            version
+        )
+    })?;
+    Ok((min, protocols.next_back().unwrap_or(min)))
+}
+
+impl fmt::Display for ProtocolRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.written)
+    }
+}
+
+impl FromStr for ProtocolRange {
+    type Err = String;
+
+    fn from_str(written: &str) -> Result<Self, Self::Err> {
+        let trimmed = written.trim();
+
+        let (min, max) = if let Some(min) = trimmed.strip_suffix('+') {
+            (release(min.trim())?.0, None)
+        } else if let Some((min, max)) = trimmed.split_once(['-', '–']) {
+            (release(min.trim())?.0, Some(release(max.trim())?.1))
+        } else {
+            let (min, max) = release(trimmed)?;
+            (min, Some(max))
+        };
+
+        if max.is_some_and(|max| max < min) {
+            return Err(format!("version range {:?} ends before it starts", written));
+        }
+
+        Ok(ProtocolRange {
+            written: written.to_owned(),
+            min,
+            max,
+        })
+    }
+}
+
+impl Serialize for ProtocolRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.written)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(RangeVisitor)
+    }
+}
+
+// unquoted versions like 1.16 are read as numbers by yaml, and 1.10 would be
+// read as 1.1, so only protocol numbers are taken without quotes
+struct RangeVisitor;
+
+impl<'de> Visitor<'de> for RangeVisitor {
+    type Value = ProtocolRange;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a version range like \"1.8-1.12\" or a protocol number")
+    }
+
+    fn visit_str<E: de::Error>(self, written: &str) -> Result<Self::Value, E> {
+        written.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, protocol: i64) -> Result<Self::Value, E> {
+        self.visit_str(&protocol.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, protocol: u64) -> Result<Self::Value, E> {
+        self.visit_str(&protocol.to_string())
+    }
+
+    fn visit_f64<E: de::Error>(self, version: f64) -> Result<Self::Value, E> {
+        Err(E::custom(format!(
+            "versions need to be quoted, like \"{}\"",
+            version
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(written: &str) -> (i32, Option<i32>) {
+        let range = written.parse::<ProtocolRange>().unwrap();
+        (range.min, range.max)
+    }
+
+    #[test]
+    fn ranges_between_releases() {
+        assert_eq!(range("1.8-1.12.2"), (47, Some(340)));
+        assert_eq!(range("1.20.5 - 1.21.4"), (766, Some(769)));
+        assert_eq!(range("1.16–1.16.5"), (735, Some(754)));
+        // a release without a patch goes up to its last patch
+        assert_eq!(range("1.8-1.12"), (47, Some(340)));
+        assert_eq!(range("1.9-1.9"), (107, Some(110)));
+    }
+
+    #[test]
+    fn open_ranges() {
+        assert_eq!(range("1.20.5+"), (766, None));
+        assert_eq!(range("1.20+"), (763, None));
+        assert_eq!(range("763+"), (763, None));
+
+        let range = "1.21+".parse::<ProtocolRange>().unwrap();
+        assert!(range.contains(774));
+        assert!(range.contains(10_000));
+        assert!(!range.contains(766));
+    }
+
+    #[test]
+    fn bare_releases_cover_every_patch() {
+        assert_eq!(range("1.20"), (763, Some(766)));
+        assert_eq!(range("1.21"), (767, Some(774)));
+        assert_eq!(range("1.12.2"), (340, Some(340)));
+        // 1.2 isn't a prefix of 1.21
+        assert!("1.2".parse::<ProtocolRange>().is_err());
+    }
+
+    #[test]
+    fn protocol_numbers() {
+        assert_eq!(range("47"), (47, Some(47)));
+        assert_eq!(range("47-340"), (47, Some(340)));
+        assert_eq!(range("1.8-340"), (47, Some(340)));
+        // numbers that aren't a known release are still fine
+        assert_eq!(range("1000"), (1000, Some(1000)));
+    }
+
+    #[test]
+    fn bad_ranges_are_refused() {
+        let error = "1.12-1.8".parse::<ProtocolRange>().unwrap_err();
+        assert!(error.contains("ends before it starts"), "{}", error);
+        assert!("340-47".parse::<ProtocolRange>().is_err());
+        // a bare release ends at its last patch, which is after 1.9.2
+        assert!("1.9.2-1.9".parse::<ProtocolRange>().is_ok());
+
+        let error = "1.99".parse::<ProtocolRange>().unwrap_err();
+        assert!(error.contains("unknown version"), "{}", error);
+        assert!("".parse::<ProtocolRange>().is_err());
+        assert!("1.8-".parse::<ProtocolRange>().is_err());
+        assert!("+".parse::<ProtocolRange>().is_err());
+    }
+
+    #[test]
+    fn releases_are_shown_by_name() {
+        let releases = |written: &str| written.parse::<ProtocolRange>().unwrap().releases();
+
+        assert_eq!(releases("1.20-1.21"), "1.20–1.21.11");
+        assert_eq!(releases("1.20.5+"), "1.20.5+");
+        assert_eq!(releases("1.12.2"), "1.12.2");
+        assert_eq!(releases("1000"), "protocol 1000");
+    }
+}
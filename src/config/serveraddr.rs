@@ -7,6 +7,16 @@ static DEFAULT_PORT_STR: &str = "25565";
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ServerAddr(Hostname, u16);
 
+impl ServerAddr {
+    pub fn hostname(&self) -> &Hostname {
+        &self.0
+    }
+
+    pub fn port(&self) -> u16 {
+        self.1
+    }
+}
+
 impl fmt::Display for ServerAddr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}:{}", self.0, self.1)?;
@@ -0,0 +1,896 @@
+//! Reading a config file with errors that point at the file, line and host
+//! they're about, rather than just what serde saw.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{
+    de::{DeserializeOwned, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use toml::Spanned;
+
+use yaml_rust2::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+use serde_yaml::Value;
+
+use super::{
+    format::{line_column, Format},
+    interpolate::{self, Template},
+    migrate, Action, Config, Listener, LoginAction, RateLimitBuckets, StatusAction, VirtualHost,
+};
+
+/// One thing wrong with a config file.
+#[derive(Debug)]
+pub struct Problem {
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Virtual host the problem is in, if any.
+    pub host: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+        }
+        write!(f, ": ")?;
+
+        if let Some(host) = &self.host {
+            write!(f, "host {}: ", host)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Every problem found in a config file, one per line when displayed.
+#[derive(Debug)]
+pub struct Invalid(pub Vec<Problem>);
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+fn problem(file: &Path, locations: &Locations, path: &str, mut message: String) -> Problem {
+    let (line, column) = match locations.get(path) {
+        Some(&(line, column)) => (Some(line), Some(column)),
+        None => (None, None),
+    };
+
+    // without a line the path is the only way to find it
+    if line.is_none() && path != "." && !message.starts_with(path) {
+        message = format!("{}: {}", path, message);
+    }
+
+    Problem {
+        file: file.to_owned(),
+        line,
+        column,
+        host: locations.host(path),
+        message,
+    }
+}
+
+/// A file's contents, and what it took to get them.
+struct Parsed<T> {
+    value: T,
+    /// Interpolated values as they were written.
+    templates: HashMap<String, Template>,
+    locations: Locations,
+    /// Version the file was written for, if it was older than the current one.
+    migrated_from: Option<u64>,
+}
+
+/// Parse a file in the format its extension says, migrating it and
+/// interpolating its values before deserializing them.
+fn deserialize<T: DeserializeOwned>(file: &Path, source: &str) -> Result<Parsed<T>, Vec<Problem>> {
+    let format = Format::of(file);
+    let mut value = format.parse(source).map_err(|error| {
+        vec![Problem {
+            file: file.to_owned(),
+            line: error.location.map(|(line, _)| line),
+            column: error.location.map(|(_, column)| column),
+            host: None,
+            message: error.message,
+        }]
+    })?;
+
+    let locations = match format {
+        Format::Toml => Locations::index_toml(source, &value),
+        Format::Yaml | Format::Json => Locations::index(source),
+    };
+
+    let migrated_from = migrate::migrate(&mut value)
+        .map_err(|message| vec![problem(file, &locations, "version", message)])?;
+
+    let dir = file.parent().unwrap_or_else(|| Path::new(""));
+    let (templates, problems) = interpolate::resolve(&mut value, dir);
+    if !problems.is_empty() {
+        return Err(problems
+            .into_iter()
+            .map(|(path, message)| problem(file, &locations, &path, message))
+            .collect());
+    }
+
+    let value = match serde_path_to_error::deserialize(value.clone()) {
+        Ok(deserialized) => deserialized,
+        Err(error) => {
+            let mut problems = every_error::<T>(value);
+            if problems.is_empty() {
+                problems.push(path_error(error, ""));
+            }
+
+            let mut problems = problems
+                .into_iter()
+                .map(|(path, message)| problem(file, &locations, &path, message))
+                .collect::<Vec<_>>();
+            problems.sort_by_key(|problem| (problem.line, problem.column));
+            return Err(problems);
+        }
+    };
+
+    Ok(Parsed {
+        value,
+        templates,
+        locations,
+        migrated_from,
+    })
+}
+
+/// Path and message of a serde error, with the path under `prefix`.
+fn path_error(
+    error: serde_path_to_error::Error<serde_yaml::Error>,
+    prefix: &str,
+) -> (String, String) {
+    let path = match (prefix, error.path().to_string().as_str()) {
+        (prefix, ".") if !prefix.is_empty() => prefix.to_owned(),
+        ("", path) => path.to_owned(),
+        (prefix, path) => format!("{}.{}", prefix, path),
+    };
+    let message = match path.as_str() {
+        "." => error.inner().to_string(),
+        _ => format!("{}: {}", path, error.inner()),
+    };
+
+    (path, message)
+}
+
+fn error_in<T: DeserializeOwned>(value: Value, prefix: &str) -> Option<(String, String)> {
+    serde_path_to_error::deserialize::<_, T>(value)
+        .err()
+        .map(|error| path_error(error, prefix))
+}
+
+/// Take the sequence under `key` out of a mapping, leaving an empty one.
+fn take_sequence(value: &mut Value, key: &str) -> Vec<Value> {
+    match value.get_mut(key) {
+        Some(Value::Sequence(sequence)) => std::mem::take(sequence),
+        _ => Vec::new(),
+    }
+}
+
+/// Every error serde finds rather than only the first, by deserializing
+/// each host and listener on their own and then whatever's left without them.
+fn every_error<T: DeserializeOwned>(mut value: Value) -> Vec<(String, String)> {
+    let mut problems = Vec::new();
+
+    for (i, host) in take_sequence(&mut value, "virtualhosts")
+        .into_iter()
+        .enumerate()
+    {
+        problems.extend(error_in::<VirtualHost>(
+            host,
+            &format!("virtualhosts[{}]", i),
+        ));
+    }
+
+    for (i, mut listener) in take_sequence(&mut value, "listeners")
+        .into_iter()
+        .enumerate()
+    {
+        let path = format!("listeners[{}]", i);
+        for (j, host) in take_sequence(&mut listener, "virtualhosts")
+            .into_iter()
+            .enumerate()
+        {
+            problems.extend(error_in::<VirtualHost>(
+                host,
+                &format!("{}.virtualhosts[{}]", path, j),
+            ));
+        }
+        problems.extend(error_in::<Listener>(listener, &path));
+    }
+
+    problems.extend(error_in::<T>(value, ""));
+    problems
+}
+
+/// Parse and check a config, reading any files it includes.
+///
+/// `file` is where the config was read from, includes are relative to it.
+pub fn parse(file: &Path, source: &str) -> Result<Config, Invalid> {
+    let Parsed {
+        value: mut config,
+        templates,
+        locations,
+        migrated_from,
+    } = deserialize::<Config>(file, source).map_err(Invalid)?;
+    config.templates = templates;
+    config.migrated_from = migrated_from;
+
+    let mut problems = Vec::new();
+    for (i, include) in config.include_paths(file).into_iter().enumerate() {
+        match included_files(&include) {
+            Ok(included) => {
+                for included in included {
+                    include_hosts(&mut config, file, &included, &mut problems);
+                }
+            }
+            Err(err) => problems.push(problem(
+                file,
+                &locations,
+                &format!("include[{}]", i),
+                format!("failed to read {}: {}", include.display(), err),
+            )),
+        }
+    }
+
+    problems.extend(
+        check(&config, &locations)
+            .into_iter()
+            .map(|(path, message)| problem(file, &locations, &path, message)),
+    );
+    problems.extend(
+        check_scripts(&mut config, file)
+            .into_iter()
+            .map(|(path, message)| problem(file, &locations, &path, message)),
+    );
+    problems.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+
+    if problems.is_empty() {
+        Ok(config)
+    } else {
+        Err(Invalid(problems))
+    }
+}
+
+/// Hosts from a file included by the main config.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostsFile {
+    #[serde(rename = "virtualhosts", default)]
+    hosts: Vec<VirtualHost>,
+}
+
+/// The file itself, or every yaml file in a directory in name order.
+fn included_files(include: &Path) -> io::Result<Vec<PathBuf>> {
+    if !include.is_dir() {
+        return Ok(vec![include.to_owned()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(include)? {
+        let path = entry?.path();
+        if is_hosts_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// Whether a file in an included directory is read, hidden files are left
+/// alone so tools can write them and rename them into place.
+pub fn is_hosts_file(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .is_none_or(|name| name.starts_with('.'));
+
+    !hidden && Format::is_config_file(path)
+}
+
+/// Add an included file's hosts to the config, as long as they aren't already defined.
+fn include_hosts(config: &mut Config, file: &Path, included: &Path, problems: &mut Vec<Problem>) {
+    let source = match fs::read_to_string(included) {
+        Ok(source) => source,
+        Err(err) => {
+            return problems.push(Problem {
+                file: included.to_owned(),
+                line: None,
+                column: None,
+                host: None,
+                message: format!("failed to read included file: {}", err),
+            })
+        }
+    };
+
+    // kept so included hosts can be shown as written, they're never saved
+    // from here so nothing else uses them
+    let Parsed {
+        value: hosts_file,
+        templates,
+        locations,
+        ..
+    } = match deserialize::<HostsFile>(included, &source) {
+        Ok(parsed) => parsed,
+        Err(invalid) => return problems.extend(invalid),
+    };
+
+    for (i, host) in hosts_file.hosts.into_iter().enumerate() {
+        let defined_in = match config.included.get(&host.hostname) {
+            Some(defined_in) => Some(defined_in.as_path()),
+            None if config.hosts.contains_key(&host.hostname) => Some(file),
+            None => None,
+        };
+
+        if let Some(defined_in) = defined_in {
+            problems.push(problem(
+                included,
+                &locations,
+                &format!("virtualhosts[{}].hostname", i),
+                format!("host is already defined in {}", defined_in.display()),
+            ));
+            continue;
+        }
+
+        // scripts are relative to the main config, where they're run from
+        let path = format!("virtualhosts[{}]", i);
+        for (path, script) in host_scripts(&path, &host) {
+            if let Err(message) = check_script(config, file, script) {
+                problems.push(problem(included, &locations, &path, message));
+            }
+        }
+
+        config
+            .included
+            .insert(host.hostname.clone(), included.to_owned());
+        config.hosts.insert(host.hostname.clone(), host);
+    }
+
+    // hosts are identified by hostname rather than position, so these
+    // are the same paths as if the hosts were in the main config
+    config.templates.extend(templates);
+}
+
+/// Everything serde can't catch, as the path of the offending value and what's wrong with it.
+fn check(config: &Config, locations: &Locations) -> Vec<(String, String)> {
+    let mut problems = Vec::new();
+
+    check_hosts("virtualhosts", locations, &mut problems);
+    if let Some(hostname) = &config.default_host {
+        if !config.hosts.contains_key(hostname) {
+            problems.push((
+                "defaulthost".to_owned(),
+                format!("default host {} isn't a virtual host", hostname),
+            ));
+        }
+    }
+
+    let mut binds = HashSet::new();
+    for (i, listener) in config.listeners.iter().enumerate() {
+        let path = format!("listeners[{}]", i);
+        check_listener(&path, listener, config, locations, &mut problems);
+
+        if !binds.insert(listener.bind) {
+            problems.push((
+                format!("{}.bind", path),
+                format!("listener {} is configured more than once", listener.bind),
+            ));
+        }
+    }
+
+    let rate_limit = &config.rate_limit;
+    if rate_limit.ipv4_prefix.is_some_and(|prefix| prefix > 32) {
+        problems.push((
+            "ratelimit.ipv4prefix".to_owned(),
+            "ipv4prefix can be at most 32".to_owned(),
+        ));
+    }
+    if rate_limit.ipv6_prefix.is_some_and(|prefix| prefix > 128) {
+        problems.push((
+            "ratelimit.ipv6prefix".to_owned(),
+            "ipv6prefix can be at most 128".to_owned(),
+        ));
+    }
+    check_buckets("ratelimit.status", &rate_limit.status, &mut problems);
+    check_buckets("ratelimit.login", &rate_limit.login, &mut problems);
+
+    problems
+}
+
+/// Hosts with the same hostname overwrite each other when read, so look for
+/// them in the file itself.
+fn check_hosts(path: &str, locations: &Locations, problems: &mut Vec<(String, String)>) {
+    let mut seen = HashSet::new();
+
+    // every host has a hostname by now, or it wouldn't have deserialized
+    for i in 0.. {
+        let hostname_path = format!("{}[{}].hostname", path, i);
+        let hostname = match locations.scalar(&hostname_path) {
+            Some(hostname) => hostname,
+            None => break,
+        };
+
+        if !seen.insert(hostname) {
+            problems.push((
+                hostname_path,
+                "hostname is already used by another host".to_owned(),
+            ));
+        }
+    }
+}
+
+fn check_listener(
+    path: &str,
+    listener: &Listener,
+    config: &Config,
+    locations: &Locations,
+    problems: &mut Vec<(String, String)>,
+) {
+    let hosts_path = format!("{}.virtualhosts", path);
+    check_hosts(&hosts_path, locations, problems);
+
+    let hosts = if listener.hosts.is_empty() {
+        &config.hosts
+    } else {
+        &listener.hosts
+    };
+
+    if let Some(hostname) = &listener.default_host {
+        if !hosts.contains_key(hostname) {
+            problems.push((
+                format!("{}.defaulthost", path),
+                format!(
+                    "default host {} of listener {} isn't one of its virtual hosts",
+                    hostname, listener.bind
+                ),
+            ));
+        }
+    }
+}
+
+/// Scripts in the main config that don't compile, included hosts are checked
+/// as they're read.
+fn check_scripts(config: &mut Config, file: &Path) -> Vec<(String, String)> {
+    let mut scripts = Vec::new();
+
+    if let Some(script) = &config.script {
+        scripts.push(("script".to_owned(), script.as_path()));
+    }
+    let hosts = config
+        .hosts
+        .values()
+        .filter(|host| !config.included.contains_key(&host.hostname));
+    for (i, host) in hosts.enumerate() {
+        let path = format!("virtualhosts[{}]", i);
+        scripts.extend(host_scripts(&path, host));
+    }
+    for (i, listener) in config.listeners.iter().enumerate() {
+        for (j, host) in listener.hosts.values().enumerate() {
+            let path = format!("listeners[{}].virtualhosts[{}]", i, j);
+            scripts.extend(host_scripts(&path, host));
+        }
+    }
+
+    let scripts = scripts
+        .into_iter()
+        .map(|(path, script)| (path, script.to_owned()))
+        .collect::<Vec<_>>();
+    scripts
+        .into_iter()
+        .filter_map(|(path, script)| Some((path, check_script(config, file, &script).err()?)))
+        .collect()
+}
+
+/// Every script a host runs, including its version rules'.
+pub(super) fn host_scripts<'a>(path: &str, host: &'a VirtualHost) -> Vec<(String, &'a Path)> {
+    let mut scripts = action_scripts(&format!("{}.action", path), &host.action);
+    for (i, rule) in host.versions.iter().enumerate() {
+        let path = format!("{}.versions[{}].action", path, i);
+        scripts.extend(action_scripts(&path, &rule.action));
+    }
+
+    scripts
+}
+
+/// Every script an action runs, by the path of its `script` key.
+fn action_scripts<'a>(path: &str, action: &'a Action) -> Vec<(String, &'a Path)> {
+    let mut scripts = Vec::new();
+
+    match action {
+        Action::Script { script } => scripts.push((format!("{}.script", path), script.as_path())),
+        Action::Conditional { status, login } => {
+            if let StatusAction::Script { script } = status {
+                scripts.push((format!("{}.status.script", path), script.as_path()));
+            }
+            if let LoginAction::Script { script } = login {
+                scripts.push((format!("{}.login.script", path), script.as_path()));
+            }
+        }
+        _ => {}
+    }
+
+    scripts
+}
+
+/// Compile a script, keeping it with the config so it's only run once the
+/// config is in use.
+#[cfg(feature = "scripting")]
+fn check_script(config: &mut Config, file: &Path, script: &Path) -> Result<(), String> {
+    config.scripts.compile(&super::relative_to(file, script))
+}
+
+#[cfg(not(feature = "scripting"))]
+fn check_script(_config: &mut Config, _file: &Path, _script: &Path) -> Result<(), String> {
+    Err("can't run scripts, the router was built without the `scripting` feature".to_owned())
+}
+
+fn check_buckets(path: &str, buckets: &RateLimitBuckets, problems: &mut Vec<(String, String)>) {
+    let buckets = [
+        ("perip", &buckets.per_ip),
+        ("persubnet", &buckets.per_subnet),
+    ];
+
+    for (key, bucket) in buckets {
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => continue,
+        };
+
+        if !bucket.rate.is_finite() || bucket.rate <= 0.0 {
+            problems.push((
+                format!("{}.{}.rate", path, key),
+                "rate must be more than 0, or an empty bucket would never refill".to_owned(),
+            ));
+        }
+        if !bucket.burst.is_finite() || bucket.burst < 1.0 {
+            problems.push((
+                format!("{}.{}.burst", path, key),
+                "burst must be at least 1, or nothing would get through".to_owned(),
+            ));
+        }
+    }
+}
+
+/// Where each value in a document starts, by the same paths
+/// serde_path_to_error uses, e.g. `virtualhosts[2].action.forward`.
+#[derive(Debug, Default)]
+struct Locations {
+    /// 1-based line and column.
+    marks: HashMap<String, (usize, usize)>,
+    scalars: HashMap<String, String>,
+    frames: Vec<Frame>,
+}
+
+#[derive(Debug)]
+enum Frame {
+    Mapping { key: Option<String> },
+    Sequence { index: usize },
+}
+
+impl Locations {
+    /// Anything that isn't valid yaml won't make it past serde, so an
+    /// incomplete index is fine.
+    fn index(source: &str) -> Self {
+        let mut locations = Self::default();
+        let _ = Parser::new_from_str(source).load(&mut locations, false);
+        locations.frames.clear();
+        locations
+    }
+
+    /// Only the values, for formats yaml_rust2 can't read.
+    fn without_marks(value: &Value) -> Self {
+        fn collect(value: &Value, path: String, scalars: &mut HashMap<String, String>) {
+            let join = |key: &str| match path.is_empty() {
+                true => key.to_owned(),
+                false => format!("{}.{}", path, key),
+            };
+
+            match value {
+                Value::String(value) => {
+                    scalars.insert(path, value.clone());
+                }
+                Value::Mapping(mapping) => {
+                    for (key, value) in mapping {
+                        if let Some(key) = key.as_str() {
+                            collect(value, join(key), scalars);
+                        }
+                    }
+                }
+                Value::Sequence(sequence) => {
+                    for (i, value) in sequence.iter().enumerate() {
+                        collect(value, format!("{}[{}]", path, i), scalars);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut locations = Self::default();
+        collect(value, String::new(), &mut locations.scalars);
+        locations
+    }
+
+    /// Toml values with where they start, from the spans toml keeps.
+    fn index_toml(source: &str, value: &Value) -> Self {
+        fn mark(source: &str, node: &Spanned<TomlNode>, path: String, locations: &mut Locations) {
+            let join = |key: &str| match path.is_empty() {
+                true => key.to_owned(),
+                false => format!("{}.{}", path, key),
+            };
+
+            match node.get_ref() {
+                TomlNode::Table(entries) => {
+                    for (key, value) in entries {
+                        mark(source, value, join(key), locations);
+                    }
+                }
+                TomlNode::Array(elements) => {
+                    for (i, value) in elements.iter().enumerate() {
+                        mark(source, value, format!("{}[{}]", path, i), locations);
+                    }
+                }
+                TomlNode::Other => {}
+            }
+
+            if !path.is_empty() {
+                let start = node.span().start;
+                locations.marks.insert(path, line_column(source, start));
+            }
+        }
+
+        let mut locations = Self::without_marks(value);
+        if let Ok(TomlNode::Table(entries)) = toml::from_str::<TomlNode>(source) {
+            for (key, value) in &entries {
+                mark(source, value, key.clone(), &mut locations);
+            }
+        }
+        locations
+    }
+
+    fn get(&self, path: &str) -> Option<&(usize, usize)> {
+        self.marks.get(path)
+    }
+
+    fn scalar(&self, path: &str) -> Option<&str> {
+        self.scalars.get(path).map(String::as_str)
+    }
+
+    /// Hostname of the virtual host the path is inside of.
+    fn host(&self, path: &str) -> Option<String> {
+        let start = path.rfind("virtualhosts[")?;
+        let end = start + path[start..].find(']')?;
+        self.scalar(&format!("{}].hostname", &path[..end]))
+            .map(str::to_owned)
+    }
+
+    fn path(&self) -> String {
+        let mut path = String::new();
+
+        for frame in &self.frames {
+            match frame {
+                Frame::Mapping { key: Some(key) } => {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                }
+                Frame::Mapping { key: None } => {}
+                Frame::Sequence { index } => path.push_str(&format!("[{}]", index)),
+            }
+        }
+
+        path
+    }
+
+    /// A value has started, returns false when it's actually a mapping key.
+    fn value(&mut self, mark: Marker) -> bool {
+        if let Some(Frame::Mapping { key: None }) = self.frames.last() {
+            return false;
+        }
+
+        if !self.frames.is_empty() {
+            self.marks
+                .insert(self.path(), (mark.line(), mark.col() + 1));
+        }
+        true
+    }
+
+    /// A value has ended, move on to the next key or element.
+    fn next(&mut self) {
+        match self.frames.last_mut() {
+            Some(Frame::Mapping { key }) => *key = None,
+            Some(Frame::Sequence { index }) => *index += 1,
+            None => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for Locations {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                if self.value(mark) {
+                    self.scalars.insert(self.path(), value);
+                    self.next();
+                } else if let Some(Frame::Mapping { key }) = self.frames.last_mut() {
+                    *key = Some(value);
+                }
+            }
+            Event::Alias(_) if self.value(mark) => self.next(),
+            Event::MappingStart(..) => {
+                self.value(mark);
+                self.frames.push(Frame::Mapping { key: None });
+            }
+            Event::SequenceStart(..) => {
+                self.value(mark);
+                self.frames.push(Frame::Sequence { index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+                self.next();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A toml document read only for where its values are, each kept in a
+/// [`Spanned`] since that's the only way toml gives out locations.
+enum TomlNode {
+    Table(Vec<(String, Spanned<TomlNode>)>),
+    Array(Vec<Spanned<TomlNode>>),
+    Other,
+}
+
+impl<'de> Deserialize<'de> for TomlNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeVisitor;
+
+        impl<'de> Visitor<'de> for NodeVisitor {
+            type Value = TomlNode;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a toml value")
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<TomlNode, E> {
+                Ok(TomlNode::Other)
+            }
+
+            fn visit_i64<E>(self, _: i64) -> Result<TomlNode, E> {
+                Ok(TomlNode::Other)
+            }
+
+            fn visit_u64<E>(self, _: u64) -> Result<TomlNode, E> {
+                Ok(TomlNode::Other)
+            }
+
+            fn visit_f64<E>(self, _: f64) -> Result<TomlNode, E> {
+                Ok(TomlNode::Other)
+            }
+
+            fn visit_str<E>(self, _: &str) -> Result<TomlNode, E> {
+                Ok(TomlNode::Other)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<TomlNode, A::Error> {
+                let mut elements = Vec::new();
+                while let Some(element) = seq.next_element()? {
+                    elements.push(element);
+                }
+                Ok(TomlNode::Array(elements))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TomlNode, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(TomlNode::Table(entries))
+            }
+        }
+
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(file: &str, source: &str) -> Vec<String> {
+        match parse(Path::new(file), source) {
+            Ok(_) => panic!("{} parsed", file),
+            Err(Invalid(problems)) => problems.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn every_bad_host_and_listener_is_reported() {
+        let problems = problems(
+            "config.yml",
+            "\
+virtualhosts:
+  - hostname: a.example.com
+    action:
+      forward: localhost:25566
+    supported: banana
+  - hostname: b.example.com
+    action:
+      forward: localhost:25567
+    supported: 1.20-
+listeners:
+  - bind: 0.0.0.0:25565
+    virtualhosts:
+      - hostname: c.example.com
+        colour: blue
+timeouts:
+  connect: soon
+",
+        );
+
+        assert_eq!(problems.len(), 4, "{:#?}", problems);
+        let lines = problems
+            .iter()
+            .map(|problem| problem.split(':').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["5", "9", "14", "16"]);
+        assert!(problems[0].contains("host a.example.com"));
+        assert!(problems[2].contains("host c.example.com"));
+    }
+
+    #[test]
+    fn toml_problems_have_lines() {
+        let problems = problems(
+            "config.toml",
+            "\
+[timeouts]
+connect = \"soon\"
+
+[[virtualhosts]]
+hostname = \"a.example.com\"
+supported = \"banana\"
+action = { forward = \"localhost:25566\" }
+",
+        );
+
+        assert_eq!(problems.len(), 2, "{:#?}", problems);
+        assert!(
+            problems[0].starts_with("config.toml:2:11: "),
+            "{}",
+            problems[0]
+        );
+        assert!(
+            problems[1].starts_with("config.toml:6:13: host a.example.com: "),
+            "{}",
+            problems[1]
+        );
+    }
+}
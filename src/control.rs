@@ -0,0 +1,156 @@
+//! Unix socket accepting the same commands as the stdin cli, for when the
+//! router runs without one, and the `mc_router ctl` client that talks to it.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::net,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task,
+};
+use tracing::{debug, error, info};
+
+use mc_router::config;
+
+use crate::{
+    cli::{self, Output},
+    ROUTER,
+};
+
+// generous for a command line, stops a client from filling memory
+static MAX_COMMAND_LENGTH: u64 = 64 * 1024;
+
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    // anything still here is from a router that has either exited or handed off already
+    let _ = fs::remove_file(path);
+
+    UnixListener::bind(path)
+}
+
+/// Run commands sent to `listener` until a newer router takes it over.
+pub async fn serve(listener: UnixListener) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = ROUTER.handed_off() => return,
+        };
+
+        match accepted {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_command(stream));
+            }
+            Err(err) => {
+                error!(%err, "Error accepting control connection");
+            }
+        }
+    }
+}
+
+/// Run the one command sent on a connection and reply with its [`Output`] as json.
+async fn handle_command(stream: UnixStream) {
+    let (read, mut write) = stream.into_split();
+
+    let mut line = String::new();
+    let mut read = BufReader::new(read).take(MAX_COMMAND_LENGTH);
+    if let Err(err) = read.read_line(&mut line).await {
+        debug!(%err, "Failed to read command from control socket");
+        return;
+    }
+
+    info!(command = line.trim(), "Running command from control socket");
+    let output = match task::spawn_blocking(move || cli::execute(&line)).await {
+        Ok(output) => output,
+        Err(err) => {
+            error!(%err, "Control command panicked");
+            return;
+        }
+    };
+
+    let mut response = serde_json::to_vec(&output).expect("output is always valid json");
+    response.push(b'\n');
+    if let Err(err) = write.write_all(&response).await {
+        debug!(%err, "Failed to reply on control socket");
+    }
+}
+
+/// Entry point for `mc_router ctl`.
+///
+/// Exits with 1 when the command failed and 2 when it couldn't be sent at all.
+pub fn ctl(json: bool, socket: Option<PathBuf>, command: Vec<String>) -> ExitCode {
+    let command = command.join(" ");
+
+    let socket = match socket.or_else(configured_socket) {
+        Some(socket) => socket,
+        None => {
+            eprintln!("No control socket configured, set controlsocket or pass --socket");
+            return ExitCode::from(2);
+        }
+    };
+
+    let response = match send_command(&socket, &command) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!(
+                "Failed to send command to router at {}: {}",
+                socket.display(),
+                err
+            );
+            return ExitCode::from(2);
+        }
+    };
+
+    if json {
+        println!("{}", response.trim_end());
+    }
+
+    let output: Output = match serde_json::from_str(&response) {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Router sent an invalid response: {}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    if !json {
+        for line in &output.lines {
+            if output.ok {
+                println!("{}", line);
+            } else {
+                eprintln!("{}", line);
+            }
+        }
+    }
+
+    if output.ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn configured_socket() -> Option<PathBuf> {
+    // loading would write a default config if there isn't one
+    if !config::exists() {
+        return None;
+    }
+
+    config::load().ok()?.control_socket
+}
+
+fn send_command(socket: &Path, command: &str) -> io::Result<String> {
+    let mut stream = net::UnixStream::connect(socket)?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
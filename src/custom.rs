@@ -0,0 +1,60 @@
+//! Routing decided by Rust code, for hosts with `custom: <name>` as their action.
+//!
+//! Implementations are registered by name with [`crate::Router::register_action`].
+
+use std::net::SocketAddr;
+
+pub use mcproto::handshake::Handshake;
+
+pub use crate::client::LoginStart;
+use crate::config::{Hostname, ServerAddr, StaticAction};
+
+/// Decides what happens to a connection.
+///
+/// Called after the handshake for status requests, and after the login start
+/// for logins. Any `Fn(&Request) -> Route` closure is one too.
+pub trait CustomAction: Send + Sync {
+    fn route(&self, request: &Request<'_>) -> Route;
+}
+
+impl<F> CustomAction for F
+where
+    F: Fn(&Request<'_>) -> Route + Send + Sync,
+{
+    fn route(&self, request: &Request<'_>) -> Route {
+        self(request)
+    }
+}
+
+/// Everything known about a connection when a custom action is asked about it.
+#[derive(Debug, Clone)]
+pub struct Request<'a> {
+    pub addr: SocketAddr,
+    /// Bind address of the listener that accepted the connection.
+    pub listener: SocketAddr,
+    /// Virtual host the action belongs to, which is the default host when the
+    /// hostname didn't match any, or the host handed off to.
+    pub host: Hostname,
+    /// Hostname the client connected with.
+    pub hostname: Hostname,
+    pub port: u16,
+    pub protocol_version: i32,
+    /// The handshake as the client sent it.
+    pub handshake: &'a Handshake,
+    /// Only sent for logins.
+    pub login_start: Option<&'a LoginStart>,
+}
+
+/// Where a custom action sends a connection.
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// Proxy to a backend server.
+    Forward(ServerAddr),
+    /// Answer the way a `static` action would.
+    Static(StaticAction),
+    /// Disconnect logins with a message, status requests show it as the description.
+    Kick(String),
+    /// Do whatever another virtual host does, which can be asking its own
+    /// custom action or script.
+    Host(Hostname),
+}
@@ -0,0 +1,357 @@
+//! Passing listening sockets between router processes so upgrades don't drop
+//! players, either from an older router over a unix socket or from systemd
+//! socket activation.
+
+use std::{
+    env, fmt, fs,
+    io::{self, IoSlice, IoSliceMut, Write},
+    net::{self, SocketAddr},
+    ops::Range,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener as StdUnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
+use tokio::{
+    io::{AsyncReadExt, Interest},
+    net::{TcpListener, UnixListener},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::ROUTER;
+
+static HANDOFF_REQUEST: &[u8] = b"mc_router handoff\n";
+static MAX_LISTENERS: usize = 64;
+// the old router answers straight away, don't hang startup if it's stuck
+static HANDOFF_TIMEOUT: Duration = Duration::from_secs(5);
+
+// first descriptor passed by systemd, see sd_listen_fds(3)
+static SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A socket that can be handed to a newer router, one per line of the handoff message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Socket {
+    /// A minecraft, admin API or metrics listener.
+    Tcp(SocketAddr),
+    /// The control socket.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Socket::Tcp(bind) => write!(f, "tcp {}", bind),
+            Socket::Unix(path) => write!(f, "unix {}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Socket {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        match line.split_once(' ') {
+            Some(("tcp", bind)) => bind
+                .parse()
+                .map(Socket::Tcp)
+                .map_err(|err| format!("invalid address {:?}: {}", bind, err)),
+            Some(("unix", path)) => Ok(Socket::Unix(path.into())),
+            _ => Err(format!("unknown socket {:?}", line)),
+        }
+    }
+}
+
+/// Listeners inherited from another process.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    tcp: Vec<(SocketAddr, net::TcpListener)>,
+    unix: Vec<(PathBuf, StdUnixListener)>,
+}
+
+impl Inherited {
+    pub fn extend(&mut self, other: Inherited) {
+        self.tcp.extend(other.tcp);
+        self.unix.extend(other.unix);
+    }
+
+    /// The inherited listener bound to `bind`, if there is one.
+    pub fn take_tcp(&mut self, bind: SocketAddr) -> Option<io::Result<TcpListener>> {
+        let index = self
+            .tcp
+            .iter()
+            .position(|(inherited, _)| *inherited == bind)?;
+        let (_, listener) = self.tcp.swap_remove(index);
+
+        Some(
+            listener
+                .set_nonblocking(true)
+                .and_then(|_| TcpListener::from_std(listener)),
+        )
+    }
+
+    /// The inherited listener for the unix socket at `path`, if there is one.
+    pub fn take_unix(&mut self, path: &Path) -> Option<io::Result<UnixListener>> {
+        let index = self
+            .unix
+            .iter()
+            .position(|(inherited, _)| inherited == path)?;
+        let (_, listener) = self.unix.swap_remove(index);
+
+        Some(
+            listener
+                .set_nonblocking(true)
+                .and_then(|_| UnixListener::from_std(listener)),
+        )
+    }
+
+    /// Close whatever wasn't taken, it's no longer configured.
+    pub fn close_rest(self) {
+        for (bind, _) in self.tcp {
+            warn!(
+                "Closing inherited listener for {}, it's no longer configured",
+                bind
+            );
+        }
+        for (path, _) in self.unix {
+            warn!(
+                "Closing inherited socket {}, it's no longer configured",
+                path.display()
+            );
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Take over the listeners of a router already running with the same handoff socket.
+///
+/// Returns nothing if there's no router on the other end, in which case any
+/// stale socket file is left for [`serve`] to replace. Blocks, so call it
+/// from outside the runtime or with `spawn_blocking`.
+pub fn take_listeners(path: &Path) -> io::Result<Inherited> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(Inherited::default())
+        }
+        Err(err) => return Err(err),
+    };
+
+    info!("Taking over listeners from running router");
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    stream.write_all(HANDOFF_REQUEST)?;
+
+    let mut payload = vec![0; 4096];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; MAX_LISTENERS]);
+    let mut iov = [IoSliceMut::new(&mut payload)];
+
+    let message = socket::recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    let mut fds = Vec::new();
+    for cmsg in message.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            // SAFETY: the descriptors were just passed to us and nothing else owns
+            // them, owning them straight away closes them if anything below fails
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+
+    if message.flags.contains(MsgFlags::MSG_CTRUNC) {
+        return Err(invalid("router sent more listeners than can be received"));
+    }
+    if message.flags.contains(MsgFlags::MSG_TRUNC) {
+        return Err(invalid("router sent a longer message than can be received"));
+    }
+    let len = message.bytes;
+
+    let payload =
+        std::str::from_utf8(&payload[..len]).map_err(|_| invalid("message isn't valid utf-8"))?;
+    let sockets = payload
+        .lines()
+        .map(str::parse::<Socket>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+
+    if sockets.len() != fds.len() {
+        return Err(invalid(format!(
+            "router sent {} sockets but described {}",
+            fds.len(),
+            sockets.len()
+        )));
+    }
+
+    let mut inherited = Inherited::default();
+    for (socket, fd) in sockets.into_iter().zip(fds) {
+        match socket {
+            Socket::Tcp(bind) => inherited.tcp.push((bind, fd.into())),
+            Socket::Unix(path) => inherited.unix.push((path, fd.into())),
+        }
+    }
+    Ok(inherited)
+}
+
+/// Descriptors passed by systemd socket activation, taking them out of the
+/// environment so nothing we spawn sees them.
+///
+/// Must be called before any other threads are started, changing the
+/// environment while they might be reading it isn't sound.
+pub fn take_systemd_fds() -> Range<RawFd> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if !for_us || count <= 0 {
+        return 0..0;
+    }
+    SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count
+}
+
+/// Listeners for the descriptors from [`take_systemd_fds`].
+pub fn systemd_listeners(fds: Range<RawFd>) -> Inherited {
+    let tcp = fds
+        .filter_map(|fd| {
+            // SAFETY: systemd hands these descriptors to us and nothing else owns them
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(bind) => Some((bind, listener)),
+                Err(err) => {
+                    warn!(fd, %err, "Ignoring socket passed by systemd");
+                    None
+                }
+            }
+        })
+        .collect();
+
+    Inherited {
+        tcp,
+        unix: Vec::new(),
+    }
+}
+
+/// Wait for a newer router to ask for our listeners, hand them over then
+/// drain until every proxied session has ended on its own.
+pub async fn serve(path: &Path, sockets: Vec<(Socket, RawFd)>) {
+    // anything still here is from a router that has either exited or handed off already
+    let _ = fs::remove_file(path);
+
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(%err, "Failed to listen on handoff socket {}", path.display());
+            return;
+        }
+    };
+    debug!("Listening for handoff on {}", path.display());
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!(%err, "Error accepting handoff connection");
+                continue;
+            }
+        };
+
+        let mut request = vec![0; HANDOFF_REQUEST.len()];
+        if stream.read_exact(&mut request).await.is_err() || request != HANDOFF_REQUEST {
+            warn!("Ignoring unknown request on handoff socket");
+            continue;
+        }
+
+        let payload = sockets
+            .iter()
+            .map(|(socket, _)| format!("{socket}\n"))
+            .collect::<String>();
+        let fds = sockets.iter().map(|(_, fd)| *fd).collect::<Vec<_>>();
+
+        let sent = loop {
+            if let Err(err) = stream.writable().await {
+                break Err(err);
+            }
+
+            match stream.try_io(Interest::WRITABLE, || {
+                socket::sendmsg::<()>(
+                    stream.as_raw_fd(),
+                    &[IoSlice::new(payload.as_bytes())],
+                    &[ControlMessage::ScmRights(&fds)],
+                    MsgFlags::empty(),
+                    None,
+                )
+                .map_err(io::Error::from)
+            }) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => break result,
+            }
+        };
+
+        if let Err(err) = sent {
+            error!(%err, "Failed to hand off listeners");
+            continue;
+        }
+
+        info!("Handed listeners off to new router");
+        ROUTER.hand_off();
+        return;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockets_round_trip() {
+        let sockets = [
+            Socket::Tcp("0.0.0.0:25565".parse().unwrap()),
+            Socket::Tcp("[::1]:9000".parse().unwrap()),
+            Socket::Unix("/run/mc router/ctl.sock".into()),
+        ];
+
+        for socket in sockets {
+            assert_eq!(socket.to_string().parse::<Socket>(), Ok(socket));
+        }
+    }
+
+    #[test]
+    fn bad_lines_are_refused() {
+        for line in [
+            "",
+            "0.0.0.0:25565",
+            "tcp",
+            "tcp nowhere",
+            "udp 0.0.0.0:25565",
+        ] {
+            assert!(line.parse::<Socket>().is_err(), "{:?} parsed", line);
+        }
+    }
+}
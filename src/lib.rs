@@ -0,0 +1,20 @@
+//! Routes Minecraft connections to backend servers by the hostname players connect with.
+//!
+//! The `mc_router` binary is one way to run it, [`Router`] runs the same
+//! router inside another program with its hosts managed from there.
+
+#[macro_use]
+extern crate lazy_static;
+
+mod client;
+pub mod config;
+pub mod custom;
+mod metrics;
+mod router;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod shutdown;
+
+pub use client::{ping, registry::ConnectionInfo};
+pub use custom::CustomAction;
+pub use router::{Router, RouterBuilder};
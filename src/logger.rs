@@ -1,3 +1,6 @@
+use std::path::Path;
+
+use clap::ValueEnum;
 use tracing::metadata::LevelFilter;
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{
@@ -7,6 +10,13 @@ use tracing_subscriber::{
     EnvFilter, Layer,
 };
 
+/// Format of the log files, stdout is always human readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Json,
+    Logfmt,
+}
+
 #[derive(Debug)]
 struct ShortTime;
 
@@ -16,7 +26,7 @@ impl FormatTime for ShortTime {
     }
 }
 
-pub fn setup() -> WorkerGuard {
+pub fn setup(log_dir: &Path, format: LogFormat) -> WorkerGuard {
     let stdout_log = {
         let filter = EnvFilter::builder()
             .with_default_directive(LevelFilter::INFO.into())
@@ -29,9 +39,18 @@ pub fn setup() -> WorkerGuard {
     };
 
     let (file_log, guard) = {
-        let file_appender = tracing_appender::rolling::daily("logs", "router.log");
+        let file_appender = tracing_appender::rolling::daily(log_dir, "router.log");
         let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-        (fmt::layer().json().with_writer(non_blocking), guard)
+
+        let layer = match format {
+            LogFormat::Json => fmt::layer().json().with_writer(non_blocking).boxed(),
+            LogFormat::Logfmt => fmt::layer()
+                .event_format(tracing_logfmt::EventsFormatter::default())
+                .fmt_fields(tracing_logfmt::FieldsFormatter::default())
+                .with_writer(non_blocking)
+                .boxed(),
+        };
+        (layer, guard)
     };
 
     tracing_subscriber::registry()
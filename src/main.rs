@@ -1,69 +1,437 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod admin;
 mod cli;
-mod client;
-mod config;
+#[cfg(unix)]
+mod control;
+#[cfg(unix)]
+mod handoff;
 mod logger;
+mod reload;
 
 use std::{
-    env,
-    net::{SocketAddr, TcpListener},
-    sync::RwLock,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Mutex,
     thread,
     time::Duration,
 };
 
-use client::spawn_client_handler;
-use config::Config;
-use tracing::{error, info};
+use clap::{Parser, Subcommand};
+use logger::LogFormat;
+use mc_router::{
+    config::{self, ServerAddr},
+    Router,
+};
+#[cfg(unix)]
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use tokio::{net::TcpListener, time};
+use tracing::{error, info, warn};
+
+// saves are written outside the config lock, one at a time so the newest wins
+static SAVING: Mutex<()> = Mutex::new(());
 
 lazy_static! {
-    static ref CONFIG: RwLock<Config> = RwLock::new(Default::default());
+    static ref ROUTER: Router = Router::builder().build();
+    static ref VERSION: String = format!(
+        "{} rev:{}",
+        env!("CARGO_PKG_VERSION"),
+        git_version::git_version!()
+    );
+}
+
+/// Routes Minecraft connections to backend servers by the hostname players connect with.
+#[derive(Debug, Parser)]
+#[command(version = VERSION.as_str())]
+struct Args {
+    /// Config file to read and save to, yaml unless it ends in .toml or .json.
+    /// [default: the first of config.yml, config.yaml, config.toml or config.json]
+    #[arg(long, short, global = true)]
+    config: Option<PathBuf>,
+    /// Extra address to listen on using the top level virtual hosts, may be repeated.
+    #[arg(long, short)]
+    bind: Vec<SocketAddr>,
+    /// Directory to write log files to.
+    #[arg(long, default_value = "logs")]
+    log_dir: PathBuf,
+    /// Format of the log files.
+    #[arg(long, value_enum, default_value_t = LogFormat::Json)]
+    log_format: LogFormat,
+    /// Don't read commands from stdin, for running as a service.
+    #[arg(long)]
+    no_stdin: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Check the config file can be read, then exit.
+    CheckConfig,
+    /// Write the config out in another format, picked by the output's extension.
+    ConvertConfig {
+        output: PathBuf,
+        /// Overwrite the output if it already exists.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show the status of a server, the same way the multiplayer menu would.
+    Ping {
+        /// Server to ping, the port defaults to 25565.
+        host: ServerAddr,
+    },
+    /// Run a cli command in a running router through its control socket.
+    #[cfg(unix)]
+    Ctl {
+        /// Print the router's response as json.
+        #[arg(long)]
+        json: bool,
+        /// Control socket to use instead of the one in the config.
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
 }
 
-fn main() {
-    let _guard = logger::setup();
+fn main() -> ExitCode {
+    let args = Args::parse();
+    config::set_path(args.config.unwrap_or_else(config::default_path));
+
+    match args.command {
+        Some(Command::CheckConfig) => return check_config(),
+        Some(Command::ConvertConfig { output, force }) => return convert_config(&output, force),
+        Some(Command::Ping { host }) => return ping(host),
+        #[cfg(unix)]
+        Some(Command::Ctl {
+            json,
+            socket,
+            command,
+        }) => return control::ctl(json, socket, command),
+        None => {}
+    }
+
+    // before the logger or cli start any threads
+    #[cfg(unix)]
+    let systemd_fds = handoff::take_systemd_fds();
+
+    let _guard = logger::setup(&args.log_dir, args.log_format);
 
     match config::load() {
-        Ok(config) => *CONFIG.write().unwrap() = config,
+        Ok(config) => {
+            if let Some(version) = config.migrated_from() {
+                warn!(
+                    "Config is from version {} and was migrated to version {}, saving it will keep a backup of the original",
+                    version,
+                    config::CURRENT_VERSION
+                );
+            }
+            ROUTER.set_config(config);
+        }
         Err(error) => {
-            info!(
+            error!(
                 "Couldn't start router, Failed to read config:\n    {}",
                 error
             );
-            return;
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // addresses on the command line are extra listeners using the top level hosts
+    let mut binds = args.bind;
+    binds.extend(ROUTER.config().listeners.iter().map(|l| l.bind));
+
+    if binds.is_empty() {
+        error!("Couldn't start router, no listeners configured and no --bind given");
+        return ExitCode::FAILURE;
+    }
+
+    // stdin closing only ends the cli, the router keeps running until stopped
+    if !args.no_stdin {
+        thread::Builder::new()
+            .name("cli".to_string())
+            .spawn(cli::start)
+            .unwrap();
+    }
+
+    run_server(
+        binds,
+        #[cfg(unix)]
+        systemd_fds,
+    )
+}
+
+/// Write the running config to its file, without holding up connections while it's written.
+fn save_config() -> color_eyre::Result<()> {
+    let _saving = SAVING.lock().unwrap();
+
+    let mut config = ROUTER.config().clone();
+    config::save(&mut config)?;
+    ROUTER.update(|current| current.mark_saved(&config));
+    Ok(())
+}
+
+fn check_config() -> ExitCode {
+    let path = config::path();
+    if !config::exists() {
+        eprintln!("{} does not exist", path.display());
+        return ExitCode::FAILURE;
+    }
+
+    match config::load() {
+        Ok(config) => {
+            println!("{} is valid", path.display());
+            if let Some(version) = config.migrated_from() {
+                println!(
+                    "it's from version {} and will be migrated to version {} when saved",
+                    version,
+                    config::CURRENT_VERSION
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{} is invalid:", path.display());
+            for line in error.to_string().lines() {
+                eprintln!("    {}", line);
+            }
+            ExitCode::FAILURE
         }
     }
+}
 
-    let addr = env::args()
-        .nth(1)
-        .expect("expected address")
-        .parse()
-        .expect("address was in unknown format");
-    thread::Builder::new()
-        .name("server".to_string())
-        .spawn(move || run_server(addr))
-        .unwrap();
+fn convert_config(output: &Path, force: bool) -> ExitCode {
+    let path = config::path();
+    if !config::exists() {
+        eprintln!("{} does not exist", path.display());
+        return ExitCode::FAILURE;
+    }
+    if output.exists() && !force {
+        eprintln!(
+            "{} already exists, pass --force to overwrite it",
+            output.display()
+        );
+        return ExitCode::FAILURE;
+    }
 
-    cli::start();
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{} is invalid:", path.display());
+            for line in error.to_string().lines() {
+                eprintln!("    {}", line);
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match config::save_to(&config, output) {
+        Ok(()) => {
+            println!("Converted {} to {}", path.display(), output.display());
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("Failed to write {}: {}", output.display(), error);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn run_server(addr: SocketAddr) {
+#[tokio::main]
+async fn ping(host: ServerAddr) -> ExitCode {
+    match mc_router::ping::ping(&host).await {
+        Ok(pong) => {
+            // show it as the server sent it if it isn't valid json
+            let status = serde_json::from_str::<serde_json::Value>(&pong.status)
+                .and_then(|status| serde_json::to_string_pretty(&status))
+                .unwrap_or(pong.status);
+
+            println!("{}", status);
+            println!("Latency: {}ms", pong.latency.as_millis());
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("Failed to ping {}: {}", host, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Listeners inherited from systemd or an older router, and the ones to hand
+/// on to a newer router.
+#[derive(Default)]
+struct Sockets {
+    #[cfg(unix)]
+    inherited: handoff::Inherited,
+    #[cfg(unix)]
+    handoff: Vec<(handoff::Socket, RawFd)>,
+}
+
+impl Sockets {
+    /// Use the inherited listener for `bind`, or bind a new one.
+    fn listen(&mut self, bind: SocketAddr) -> io::Result<TcpListener> {
+        #[cfg(unix)]
+        let listener = match self.inherited.take_tcp(bind) {
+            Some(listener) => {
+                info!("Inherited listener for {}", bind);
+                listener?
+            }
+            None => ROUTER.bind(bind)?,
+        };
+        #[cfg(not(unix))]
+        let listener = ROUTER.bind(bind)?;
+
+        #[cfg(unix)]
+        self.handoff
+            .push((handoff::Socket::Tcp(bind), listener.as_raw_fd()));
+        Ok(listener)
+    }
+}
+
+#[tokio::main]
+async fn run_server(binds: Vec<SocketAddr>, #[cfg(unix)] systemd_fds: Range<RawFd>) -> ExitCode {
     info!("Starting router rev:{}...", git_version::git_version!());
-    thread::sleep(Duration::from_millis(250));
+    time::sleep(Duration::from_millis(250)).await;
 
-    let listener = TcpListener::bind(addr).unwrap();
-    info!("Listening on {}", addr);
+    tokio::spawn(handle_signals());
+    tokio::spawn(reload::watch(ROUTER.clone()));
 
-    loop {
-        match listener.accept() {
-            Ok((stream, addr)) => {
-                spawn_client_handler(stream, addr);
+    let mut sockets = Sockets::default();
+
+    #[cfg(unix)]
+    let handoff_socket = ROUTER.config().handoff_socket.clone();
+    #[cfg(unix)]
+    {
+        sockets.inherited = handoff::systemd_listeners(systemd_fds);
+        if let Some(path) = handoff_socket.clone() {
+            match tokio::task::spawn_blocking(move || handoff::take_listeners(&path)).await {
+                Ok(Ok(listeners)) => sockets.inherited.extend(listeners),
+                Ok(Err(err)) => error!(%err, "Failed to take over listeners, binding new ones"),
+                Err(err) => error!(%err, "Taking over listeners panicked, binding new ones"),
+            }
+        }
+    }
+
+    let metrics_bind = ROUTER.config().metrics_bind;
+    if let Some(bind) = metrics_bind {
+        match sockets.listen(bind) {
+            Ok(listener) => {
+                let router = ROUTER.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = router.serve_metrics(listener).await {
+                        error!(%err, "Metrics server stopped");
+                    }
+                });
+            }
+            Err(err) => error!(%err, "Failed to serve metrics on {}", bind),
+        }
+    }
+
+    let admin_bind = ROUTER.config().admin_bind;
+    if let Some(bind) = admin_bind.filter(|&bind| {
+        let allowed = admin::can_serve(bind);
+        if !allowed {
+            error!(
+                "Not serving admin API on {}, set admintoken to listen anywhere but loopback",
+                bind
+            );
+        }
+        allowed
+    }) {
+        match sockets.listen(bind) {
+            Ok(listener) => {
+                tokio::spawn(admin::serve(listener, bind));
+            }
+            Err(err) => error!(%err, "Failed to serve admin API on {}", bind),
+        }
+    }
+
+    let mut listening = 0;
+    for bind in binds {
+        match sockets.listen(bind) {
+            Ok(listener) => {
+                info!("Listening on {}", bind);
+                tokio::spawn(serve(listener, bind));
+                listening += 1;
             }
             Err(err) => {
-                error!(error = ?err, "Error accepting connection, {}", err);
+                error!(error = ?err, "Failed to listen on {}, {}", bind, err);
             }
         }
     }
+
+    if listening == 0 {
+        error!("Couldn't start router, no listeners could be bound");
+        return ExitCode::FAILURE;
+    }
+
+    #[cfg(unix)]
+    let control_socket = ROUTER.config().control_socket.clone();
+    #[cfg(unix)]
+    if let Some(path) = control_socket {
+        let listener = match sockets.inherited.take_unix(&path) {
+            Some(listener) => listener,
+            None => control::bind(&path),
+        };
+
+        match listener {
+            Ok(listener) => {
+                info!("Listening for commands on {}", path.display());
+                sockets
+                    .handoff
+                    .push((handoff::Socket::Unix(path), listener.as_raw_fd()));
+                tokio::spawn(control::serve(listener));
+            }
+            Err(err) => {
+                error!(%err, "Failed to listen on control socket {}", path.display());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        let Sockets { inherited, handoff } = sockets;
+        inherited.close_rest();
+
+        if let Some(path) = handoff_socket {
+            tokio::spawn(async move { handoff::serve(&path, handoff).await });
+        }
+    }
+
+    ROUTER.stopped().await;
+    let (drained, cut) = ROUTER.drain().await;
+    info!("Router stopped, {drained} sessions drained and {cut} cut");
+    ExitCode::SUCCESS
+}
+
+async fn serve(listener: TcpListener, bind: SocketAddr) {
+    if let Err(err) = ROUTER.serve(listener).await {
+        error!(error = ?err, "Failed to listen on {}, {}", bind, err);
+    }
+}
+
+/// Request a shutdown on SIGINT or SIGTERM, a second signal cuts the drain short.
+async fn handle_signals() {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to listen for SIGTERM");
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+
+        ROUTER.shutdown();
+    }
 }
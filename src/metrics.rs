@@ -0,0 +1,98 @@
+//! Prometheus metrics, served over HTTP at `/metrics` when `metricsbind` is set.
+
+use std::{future::Future, io};
+
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use tokio::net::TcpListener;
+use tracing::info;
+
+lazy_static! {
+    pub static ref CONNECTIONS_ACCEPTED: IntCounterVec = register_int_counter_vec!(
+        "mc_router_connections_accepted_total",
+        "Connections accepted, by listener",
+        &["listener"]
+    )
+    .unwrap();
+    pub static ref HANDSHAKES: IntCounterVec = register_int_counter_vec!(
+        "mc_router_handshakes_total",
+        "Handshakes received, by protocol version and next state",
+        &["protocol_version", "next_state"]
+    )
+    .unwrap();
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "mc_router_requests_total",
+        "Status and login requests, by the virtual host that handled them",
+        &["host", "kind"]
+    )
+    .unwrap();
+    pub static ref ACTIVE_SESSIONS: IntGaugeVec = register_int_gauge_vec!(
+        "mc_router_active_sessions",
+        "Sessions currently being proxied, by backend",
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BYTES_PROXIED: IntCounterVec = register_int_counter_vec!(
+        "mc_router_proxied_bytes_total",
+        "Bytes copied between clients and backends, by direction",
+        &["direction"]
+    )
+    .unwrap();
+    pub static ref BACKEND_CONNECT_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "mc_router_backend_connect_failures_total",
+        "Failed attempts to connect to a backend",
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref LEGACY_PINGS: IntCounter = register_int_counter!(
+        "mc_router_legacy_pings_total",
+        "Pre-netty server list pings answered"
+    )
+    .unwrap();
+}
+
+/// Counts towards the backend's active sessions until dropped.
+#[derive(Debug)]
+pub struct ActiveSession(IntGauge);
+
+pub fn track_session(backend: &str) -> ActiveSession {
+    let gauge = ACTIVE_SESSIONS.with_label_values(&[backend]);
+    gauge.inc();
+    ActiveSession(gauge)
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    stop: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    let app = Router::new().route("/metrics", get(render));
+    axum::serve(listener, app)
+        .with_graceful_shutdown(stop)
+        .await
+}
+
+async fn render() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    // only fails if the metrics themselves are malformed
+    encoder.encode(&prometheus::gather(), &mut body).unwrap();
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        body,
+    )
+}
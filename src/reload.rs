@@ -0,0 +1,244 @@
+//! Reloading the config while running, whenever the file changes or on SIGHUP.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use indexmap::IndexMap;
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer,
+};
+use tokio::sync::Notify;
+use tracing::{error, info, info_span, warn};
+
+use mc_router::{
+    config::{self, Config, Hostname, VirtualHost},
+    Router,
+};
+
+use crate::admin;
+
+// editors tend to write a file in a few steps, wait for them to finish
+static DEBOUNCE: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    static ref CHANGED: Notify = Notify::new();
+}
+
+/// Read the config file again and swap it into `router`, logging what changed.
+///
+/// The running config is kept if the new one can't be read, isn't valid or
+/// would leave the admin API served beyond loopback without a token.
+pub fn reload(router: &Router) -> Result<()> {
+    // loading would replace a missing file with an empty config
+    if !config::exists() {
+        return Err(eyre!("{} doesn't exist", config::path().display()));
+    }
+    let config = config::load()?;
+    if let Some(bind) = admin::exposed_on().filter(|_| config.admin_token.is_none()) {
+        return Err(eyre!(
+            "admintoken can't be removed while the admin API is served on {}",
+            bind
+        ));
+    }
+
+    let old = router.set_config(config);
+    log_diff(&old, &router.config());
+
+    Ok(())
+}
+
+fn log_diff(old: &Config, new: &Config) {
+    let mut changes = diff_hosts(&old.hosts, &new.hosts);
+
+    if old.default_host() != new.default_host() {
+        match new.default_host() {
+            Some(hostname) => info!(%hostname, "Default host changed"),
+            None => info!("Default host removed"),
+        }
+        changes += 1;
+    }
+
+    let no_hosts = IndexMap::new();
+    for listener in &new.listeners {
+        let _span = info_span!("listener", bind = %listener.bind).entered();
+        let old_hosts = old
+            .get_listener(&listener.bind)
+            .map_or(&no_hosts, |old| &old.hosts);
+        changes += diff_hosts(old_hosts, &listener.hosts);
+    }
+
+    info!("Reloaded config, {} host changes", changes);
+
+    // listeners, the admin API, metrics and sockets are only set up at startup
+    let binds = |config: &Config| {
+        config
+            .listeners
+            .iter()
+            .map(|listener| listener.bind)
+            .collect::<HashSet<_>>()
+    };
+    if binds(old) != binds(new) {
+        warn!("Listeners changed, restart the router for them to take effect");
+    }
+
+    let restart = [
+        ("adminbind", old.admin_bind != new.admin_bind),
+        ("metricsbind", old.metrics_bind != new.metrics_bind),
+        ("controlsocket", old.control_socket != new.control_socket),
+        ("handoffsocket", old.handoff_socket != new.handoff_socket),
+    ];
+    for (key, _) in restart.iter().filter(|(_, changed)| *changed) {
+        warn!("{} changed, restart the router for it to take effect", key);
+    }
+}
+
+/// Log each host added, changed or removed, returning how many were.
+fn diff_hosts(
+    old: &IndexMap<Hostname, VirtualHost>,
+    new: &IndexMap<Hostname, VirtualHost>,
+) -> usize {
+    let mut changes = 0;
+
+    for (hostname, host) in new {
+        match old.get(hostname) {
+            None => info!(%hostname, "Host added"),
+            Some(old) if old != host => info!(%hostname, "Host changed"),
+            Some(_) => continue,
+        }
+        changes += 1;
+    }
+
+    for hostname in old.keys() {
+        if !new.contains_key(hostname) {
+            info!(%hostname, "Host removed");
+            changes += 1;
+        }
+    }
+
+    changes
+}
+
+fn start_watching(paths: &[PathBuf]) -> Option<Debouncer<RecommendedWatcher>> {
+    match watch_paths(paths) {
+        Ok(debouncer) => Some(debouncer),
+        Err(error) => {
+            error!(
+                "Failed to watch the config files, they won't be reloaded when they change:\n    {}",
+                error
+            );
+            None
+        }
+    }
+}
+
+/// The file as an absolute path, without needing it to exist right now.
+fn absolute(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    Some(parent.canonicalize().ok()?.join(path.file_name()?))
+}
+
+/// The config file, everything it includes and the scripts it runs, which
+/// are compiled again when the config is reloaded.
+fn watched_paths(router: &Router) -> Vec<PathBuf> {
+    let path = config::path();
+    let config = router.config();
+    let mut paths = config.include_paths(&path);
+    paths.extend(config.script_paths(&path));
+    paths.insert(0, path);
+    paths
+}
+
+fn watch_paths(paths: &[PathBuf]) -> Result<Debouncer<RecommendedWatcher>> {
+    let mut files = HashSet::new();
+    let mut dirs = HashSet::new();
+
+    for path in paths {
+        let path = absolute(path)
+            .ok_or_else(|| eyre!("the directory of {} doesn't exist", path.display()))?;
+        if path.is_dir() {
+            dirs.insert(path);
+        } else {
+            files.insert(path);
+        }
+    }
+
+    // watching the directory rather than the file still sees editors that
+    // save by replacing the file
+    let watch = files
+        .iter()
+        .map(|file| file.parent().unwrap().to_owned())
+        .chain(dirs.iter().cloned())
+        .collect::<HashSet<_>>();
+
+    let mut debouncer = new_debouncer(DEBOUNCE, move |events: DebounceEventResult| {
+        let changed = |path: &PathBuf| {
+            files.contains(path)
+                || (path.parent().is_some_and(|dir| dirs.contains(dir))
+                    && config::is_hosts_file(path))
+        };
+
+        match events {
+            Ok(events) if events.iter().any(|event| changed(&event.path)) => CHANGED.notify_one(),
+            Ok(_) => {}
+            Err(err) => warn!(%err, "Error watching config files"),
+        }
+    })?;
+    for dir in watch {
+        debouncer
+            .watcher()
+            .watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok(debouncer)
+}
+
+/// Reload whenever the config, an included file or a script changes, or
+/// SIGHUP is received, until the router stops.
+pub async fn watch(router: Router) {
+    let mut paths = watched_paths(&router);
+    let mut debouncer = start_watching(&paths);
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to listen for SIGHUP");
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = CHANGED.notified() => info!("Config changed, reloading"),
+            _ = hangup.recv() => info!("Received SIGHUP, reloading config"),
+        }
+
+        #[cfg(not(unix))]
+        {
+            CHANGED.notified().await;
+            info!("Config changed, reloading");
+        }
+
+        if let Err(error) = reload(&router) {
+            error!(
+                "Failed to reload config, keeping the current one:\n    {}",
+                error
+            );
+            continue;
+        }
+
+        let reloaded = watched_paths(&router);
+        if reloaded != paths {
+            paths = reloaded;
+            // drop the old watcher first so nothing is watched twice
+            drop(debouncer.take());
+            debouncer = start_watching(&paths);
+        }
+    }
+}
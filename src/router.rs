@@ -0,0 +1,296 @@
+//! Running the router from inside another program.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, RwLock, RwLockReadGuard},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+
+use crate::{
+    client::{
+        ratelimit::{self, RateLimiter},
+        registry::{self, ConnectionInfo, Registry},
+        spawn_client_handler, Client,
+    },
+    config::{Action, Config, Hostname, VirtualHost},
+    custom::CustomAction,
+    metrics,
+    shutdown::Shutdown,
+};
+
+/// A router, with its own config, connections, rate limits and shutdown.
+///
+/// Cloning gives another handle to the same router. Separate routers built
+/// in one process don't share anything but their Prometheus metrics.
+#[derive(Clone)]
+pub struct Router {
+    inner: Arc<Inner>,
+}
+
+/// Everything a router owns, shared with each of its connections.
+pub(crate) struct Inner {
+    pub config: RwLock<Config>,
+    pub connections: Registry,
+    pub limits: RateLimiter,
+    pub shutdown: Shutdown,
+    pub actions: RwLock<HashMap<String, Arc<dyn CustomAction>>>,
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router").finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inner").finish_non_exhaustive()
+    }
+}
+
+impl Inner {
+    pub fn action(&self, name: &str) -> Option<Arc<dyn CustomAction>> {
+        self.actions.read().unwrap().get(name).cloned()
+    }
+}
+
+/// Config for a [`Router`], either a whole [`Config`] or hosts added one at a time.
+#[derive(Default)]
+pub struct RouterBuilder {
+    config: Config,
+    actions: HashMap<String, Arc<dyn CustomAction>>,
+}
+
+impl RouterBuilder {
+    /// Start from an existing config, like one read with [`crate::config::load`].
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Add a virtual host, replacing any with the same hostname.
+    pub fn host(mut self, hostname: Hostname, action: Action) -> Self {
+        self.config.hosts.insert(
+            hostname.clone(),
+            VirtualHost {
+                hostname,
+                action,
+                versions: Vec::new(),
+                supported: None,
+            },
+        );
+        self
+    }
+
+    /// Host for connections whose hostname doesn't match any other.
+    pub fn default_host(mut self, hostname: Hostname) -> Self {
+        self.config.set_default_host(Some(hostname));
+        self
+    }
+
+    /// Register a custom action, see [`Router::register_action`].
+    pub fn action(mut self, name: impl Into<String>, action: impl CustomAction + 'static) -> Self {
+        self.actions.insert(name.into(), Arc::new(action));
+        self
+    }
+
+    pub fn build(self) -> Router {
+        Router {
+            inner: Arc::new(Inner {
+                config: RwLock::new(self.config),
+                connections: Registry::default(),
+                limits: RateLimiter::default(),
+                shutdown: Shutdown::default(),
+                actions: RwLock::new(self.actions),
+            }),
+        }
+    }
+}
+
+impl Router {
+    pub fn builder() -> RouterBuilder {
+        RouterBuilder::default()
+    }
+
+    pub fn new(config: Config) -> Self {
+        Self::builder().config(config).build()
+    }
+
+    pub fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.inner.config.read().unwrap()
+    }
+
+    /// Change the config in place, returning whatever `update` does.
+    ///
+    /// Changes are used by the next connection, ones already routed keep going where they are.
+    pub fn update<T>(&self, update: impl FnOnce(&mut Config) -> T) -> T {
+        update(&mut self.inner.config.write().unwrap())
+    }
+
+    /// Swap in a whole new config, returning the old one.
+    pub fn set_config(&self, config: Config) -> Config {
+        self.update(|current| std::mem::replace(current, config))
+    }
+
+    /// Add or replace a virtual host, returning the one it replaced.
+    pub fn set_host(&self, hostname: Hostname, action: Action) -> Option<VirtualHost> {
+        self.update(|config| {
+            config.hosts.insert(
+                hostname.clone(),
+                VirtualHost {
+                    hostname,
+                    action,
+                    versions: Vec::new(),
+                    supported: None,
+                },
+            )
+        })
+    }
+
+    pub fn remove_host(&self, hostname: &Hostname) -> Option<VirtualHost> {
+        self.update(|config| config.hosts.shift_remove(hostname))
+    }
+
+    /// Use `action` for hosts with `custom: <name>` as their action, replacing
+    /// any registered with the same name.
+    ///
+    /// Hosts naming an action that isn't registered close their connections.
+    pub fn register_action(&self, name: impl Into<String>, action: impl CustomAction + 'static) {
+        self.inner
+            .actions
+            .write()
+            .unwrap()
+            .insert(name.into(), Arc::new(action));
+    }
+
+    /// Bind a listener the same way the router binds its own.
+    ///
+    /// Must be called from inside a tokio runtime.
+    pub fn bind(&self, bind: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(bind), Type::STREAM, Some(Protocol::TCP))?;
+
+        // lets 0.0.0.0 and [::] be listened on at the same time
+        if bind.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&bind.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Accept and route connections on `listener` until the router is shut down.
+    ///
+    /// Connections use the config's listener with the same address if there
+    /// is one, and the top level hosts otherwise.
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        let bind = listener.local_addr()?;
+        let shutdown = &self.inner.shutdown;
+        let mut kicking = false;
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.requested(), if !kicking => {
+                    if shutdown.kick_message(&self.config()).is_some() {
+                        kicking = true;
+                        continue;
+                    }
+
+                    info!("Stopped listening on {}", bind);
+                    return Ok(());
+                }
+            };
+
+            match accepted {
+                Ok((stream, addr)) => {
+                    metrics::CONNECTIONS_ACCEPTED
+                        .with_label_values(&[&bind.to_string()])
+                        .inc();
+
+                    let permit = match ratelimit::admit(&self.inner, addr) {
+                        Some(permit) => permit,
+                        None => {
+                            debug!(%addr, "Over the connection cap, closing");
+                            continue;
+                        }
+                    };
+
+                    let client = Client {
+                        addr,
+                        listener: bind,
+                        permit,
+                        tracked: registry::register(&self.inner, addr, bind),
+                        router: self.inner.clone(),
+                    };
+                    spawn_client_handler(stream, client);
+                }
+                Err(err) => {
+                    error!(error = ?err, "Error accepting connection, {}", err);
+                }
+            }
+        }
+    }
+
+    /// Serve Prometheus metrics on `listener` until another router takes over.
+    ///
+    /// Metrics are process wide, any router can serve them.
+    pub async fn serve_metrics(&self, listener: TcpListener) -> io::Result<()> {
+        let router = self.clone();
+        metrics::serve(listener, async move { router.handed_off().await }).await
+    }
+
+    /// Stop accepting and start draining, or give up draining if a shutdown
+    /// was already requested.
+    pub fn shutdown(&self) {
+        self.inner.shutdown.request();
+    }
+
+    /// Stop accepting because another router has taken over the listeners, then
+    /// let proxied sessions end however long that takes.
+    pub fn hand_off(&self) {
+        self.inner.shutdown.hand_off();
+    }
+
+    /// Resolves once a shutdown has been requested.
+    pub async fn stopped(&self) {
+        self.inner.shutdown.requested().await
+    }
+
+    /// Resolves once [`Router::hand_off`] has been called, for anything else
+    /// listening on sockets the new router was given to stop accepting.
+    pub async fn handed_off(&self) {
+        self.inner.shutdown.handed_off().await
+    }
+
+    /// Wait for proxied sessions to end, up to the configured deadline.
+    ///
+    /// Returns how many sessions finished on their own and how many were still
+    /// running when the router gave up on them.
+    pub async fn drain(&self) -> (usize, usize) {
+        let deadline = self.config().shutdown.deadline();
+        self.inner.shutdown.drain(deadline).await
+    }
+
+    /// All open connections, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.inner.connections.list()
+    }
+
+    /// Close both sides of a connection, returns false if there's no such connection.
+    pub fn kick(&self, id: u64) -> bool {
+        self.inner.connections.kick(id)
+    }
+
+    /// Kick every connection being handled by a virtual host, returns how many were kicked.
+    pub fn kick_host(&self, hostname: &Hostname) -> usize {
+        self.inner.connections.kick_host(hostname)
+    }
+}
@@ -0,0 +1,291 @@
+//! Routing decided by rhai scripts, for hosts with `script: <path>` as their
+//! action and the config's top level `script`.
+//!
+//! Scripts see the connection as constants and evaluate to where it goes:
+//!
+//! - `hostname`, `port` and `protocol_version` from the handshake
+//! - `host`, the virtual host that matched, `()` when none did
+//! - `ip`, the client's address without the port
+//! - `username`, `()` for status requests
+//! - `next`, either `"status"` or `"login"`
+//!
+//! A string forwards to that address. A map with one of `forward`, `host`,
+//! `kick` or `static` does what the [`Route`] of the same name does. `()`
+//! leaves the connection to its virtual host when it's the top level script,
+//! and closes it otherwise.
+//!
+//! ```rhai
+//! if protocol_version < 763 { #{ kick: "Update to 1.20 to join" } }
+//! else if username == "admin" { "staging.local:25565" }
+//! ```
+//!
+//! `static` is a keyword in rhai, so it's written `#{ "static": #{ ... } }`.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use tracing::debug;
+
+use crate::{
+    config::{self, Hostname, StaticAction},
+    custom::{Request, Route},
+    router::Inner,
+};
+
+// plenty for routing, stops a runaway loop from holding up a runtime thread
+const MAX_OPERATIONS: u64 = 100_000;
+
+lazy_static! {
+    static ref ENGINE: Engine = engine();
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.register_fn("unix_time", || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64)
+    });
+
+    engine
+}
+
+/// Scripts compiled when a config was read, kept with it so they're only
+/// run once that config is the one in use.
+#[derive(Clone, Default)]
+pub(crate) struct Scripts(HashMap<PathBuf, Arc<AST>>);
+
+impl fmt::Debug for Scripts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl Scripts {
+    /// Compile the script at `path` to find any syntax errors, keeping it for
+    /// connections to run.
+    pub fn compile(&mut self, path: &Path) -> Result<(), String> {
+        let ast = compile(path)?;
+        self.0.insert(path.to_owned(), ast);
+        Ok(())
+    }
+}
+
+fn compile(path: &Path) -> Result<Arc<AST>, String> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read script {}: {}", path.display(), err))?;
+
+    ENGINE
+        .compile(source)
+        .map(Arc::new)
+        .map_err(|err| format!("script {} doesn't compile: {}", path.display(), err))
+}
+
+/// Run a script from the config on a connection, `host` being the virtual host
+/// that matched if any did.
+///
+/// Scripts run on the blocking pool, they can take up to [`MAX_OPERATIONS`].
+pub(crate) async fn run(
+    router: &Inner,
+    script: &Path,
+    request: &Request<'_>,
+    host: Option<&Hostname>,
+) -> Result<Option<Route>, String> {
+    let path = config::relative_to(&config::path(), script);
+    let mut scope = scope(request, host);
+    let compiled = router.config.read().unwrap().scripts.0.get(&path).cloned();
+
+    let (path, result) = tokio::task::spawn_blocking(move || {
+        // only for hosts that weren't read from a file, like an embedded router's
+        let result = match compiled {
+            Some(ast) => Ok(ast),
+            None => compile(&path),
+        }
+        .and_then(|ast| {
+            ENGINE
+                .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+                .map_err(|err| format!("script {} failed: {}", path.display(), err))
+        });
+
+        (path, result)
+    })
+    .await
+    .map_err(|err| format!("script {} panicked: {}", script.display(), err))?;
+
+    let result = result?;
+    debug!(?result, "Script {} finished", path.display());
+
+    route(result).map_err(|err| format!("script {} {}", path.display(), err))
+}
+
+/// The connection as the constants scripts see.
+fn scope(request: &Request<'_>, host: Option<&Hostname>) -> Scope<'static> {
+    let mut scope = Scope::new();
+    scope.push_constant("hostname", request.hostname.to_string());
+    scope.push_constant("port", request.port as i64);
+    scope.push_constant("protocol_version", request.protocol_version as i64);
+    scope.push_constant("ip", request.addr.ip().to_string());
+    scope.push_constant_dynamic(
+        "host",
+        host.map_or(Dynamic::UNIT, |host| host.to_string().into()),
+    );
+    scope.push_constant_dynamic(
+        "username",
+        request.login_start.map_or(Dynamic::UNIT, |login_start| {
+            login_start.username.clone().into()
+        }),
+    );
+    scope.push_constant(
+        "next",
+        if request.login_start.is_some() {
+            "login"
+        } else {
+            "status"
+        },
+    );
+
+    scope
+}
+
+/// What a script evaluated to as a route, errors finish "script <path> ...".
+fn route(result: Dynamic) -> Result<Option<Route>, String> {
+    if result.is_unit() {
+        return Ok(None);
+    }
+    if result.is_string() {
+        let target = result.into_string().unwrap();
+        return target
+            .parse()
+            .map(|target| Some(Route::Forward(target)))
+            .map_err(|err| format!("returned a bad forward target: {}", err));
+    }
+
+    let type_name = result.type_name();
+    let map = result.try_cast::<Map>().ok_or_else(|| {
+        format!(
+            "returned a {}, expected a string, map or nothing",
+            type_name
+        )
+    })?;
+    if map.len() != 1 {
+        return Err(
+            "returned a map without exactly one of forward, host, kick or static".to_owned(),
+        );
+    }
+
+    let (key, value) = map.into_iter().next().unwrap();
+    let string = |value: Dynamic| {
+        value
+            .into_string()
+            .map_err(|type_name| format!("returned a {} for {}, expected a string", type_name, key))
+    };
+
+    match key.as_str() {
+        "forward" => string(value)?
+            .parse()
+            .map(Route::Forward)
+            .map_err(|err| format!("returned a bad forward target: {}", err)),
+        "host" => string(value)?
+            .parse::<Hostname>()
+            .map(Route::Host)
+            .map_err(|err| format!("returned a bad host: {}", err)),
+        "kick" => string(value).map(Route::Kick),
+        "static" => rhai::serde::from_dynamic::<StaticAction>(&value)
+            .map(Route::Static)
+            .map_err(|err| format!("returned a bad static action: {}", err)),
+        other => Err(format!(
+            "returned a map with {}, expected one of forward, host, kick or static",
+            other
+        )),
+    }
+    .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routed(script: &str) -> Result<Option<Route>, String> {
+        route(ENGINE.eval::<Dynamic>(script).unwrap())
+    }
+
+    #[test]
+    fn nothing_leaves_it_to_the_host() {
+        assert!(matches!(routed("()"), Ok(None)));
+        assert!(matches!(routed("if false { 1 }"), Ok(None)));
+    }
+
+    #[test]
+    fn strings_forward() {
+        match routed(r#""play.example.com:25566""#) {
+            Ok(Some(Route::Forward(target))) => {
+                assert_eq!(target.to_string(), "play.example.com:25566")
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let error = routed(r#""not an address:port""#).unwrap_err();
+        assert!(
+            error.starts_with("returned a bad forward target"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn maps_route_by_their_key() {
+        match routed(r#"#{ forward: "localhost:25567" }"#) {
+            Ok(Some(Route::Forward(target))) => assert_eq!(target.to_string(), "localhost:25567"),
+            other => panic!("{:?}", other),
+        }
+        match routed(r#"#{ host: "lobby.example.com" }"#) {
+            Ok(Some(Route::Host(host))) => assert_eq!(host.to_string(), "lobby.example.com"),
+            other => panic!("{:?}", other),
+        }
+        match routed(r#"#{ kick: "Come back later" }"#) {
+            Ok(Some(Route::Kick(message))) => assert_eq!(message, "Come back later"),
+            other => panic!("{:?}", other),
+        }
+        match routed(r#"#{ "static": #{ description: "Closed", max_players: 0 } }"#) {
+            Ok(Some(Route::Static(r#static))) => {
+                assert_eq!(r#static.description.as_deref(), Some("Closed"));
+                assert_eq!(r#static.max_players, Some(0));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_maps_and_types_are_errors() {
+        for (script, error) in [
+            ("42", "returned a i64, expected a string, map or nothing"),
+            ("#{}", "returned a map without exactly one of"),
+            (
+                r#"#{ forward: "a:1", kick: "no" }"#,
+                "returned a map without exactly one of",
+            ),
+            (
+                r#"#{ route: "a:1" }"#,
+                "returned a map with route, expected one of",
+            ),
+            ("#{ kick: 1 }", "returned a i64 for kick, expected a string"),
+            (r#"#{ host: "" }"#, "returned a bad host"),
+            (
+                r#"#{ "static": #{ players: 1 } }"#,
+                "returned a bad static action",
+            ),
+        ] {
+            match routed(script) {
+                Err(message) => assert!(message.starts_with(error), "{}: {}", script, message),
+                Ok(route) => panic!("{} routed to {:?}", script, route),
+            }
+        }
+    }
+}
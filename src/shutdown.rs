@@ -0,0 +1,194 @@
+use std::{
+    future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{watch, Notify},
+    time,
+};
+use tracing::{info, warn};
+
+use crate::{config::Config, router::Inner};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    Running,
+    /// Not accepting, waiting for proxied sessions to end.
+    Draining,
+    /// Stop waiting and drop whatever is left.
+    Cutting,
+}
+
+/// Where a router is in shutting down, and the proxied sessions it's waiting on.
+#[derive(Debug)]
+pub struct Shutdown {
+    state: watch::Sender<State>,
+    active_sessions: AtomicUsize,
+    ended_sessions: AtomicUsize,
+    handed_off: watch::Sender<bool>,
+    session_ended: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            state: watch::channel(State::Running).0,
+            active_sessions: AtomicUsize::new(0),
+            ended_sessions: AtomicUsize::new(0),
+            handed_off: watch::channel(false).0,
+            session_ended: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Start shutting down, or give up draining if a shutdown was already requested.
+    pub fn request(&self) {
+        self.state.send_modify(|state| match state {
+            State::Running => {
+                info!("Stopping router...");
+                *state = State::Draining;
+            }
+            State::Draining => {
+                warn!("Stop requested again, cutting remaining sessions");
+                *state = State::Cutting;
+            }
+            State::Cutting => {}
+        });
+    }
+
+    /// Stop accepting because another router has taken over the listeners, then
+    /// wait for every proxied session to end however long that takes.
+    pub fn hand_off(&self) {
+        self.handed_off.send_replace(true);
+        self.state.send_if_modified(|state| {
+            let running = *state == State::Running;
+            if running {
+                *state = State::Draining;
+            }
+            running
+        });
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.state.borrow() != State::Running
+    }
+
+    fn is_handed_off(&self) -> bool {
+        *self.handed_off.borrow()
+    }
+
+    /// Resolves once a shutdown has been requested.
+    pub async fn requested(&self) {
+        let _ = self
+            .state
+            .subscribe()
+            .wait_for(|state| *state >= State::Draining)
+            .await;
+    }
+
+    /// Resolves once another router has taken over the listeners.
+    pub async fn handed_off(&self) {
+        let _ = self
+            .handed_off
+            .subscribe()
+            .wait_for(|handed_off| *handed_off)
+            .await;
+    }
+
+    async fn cut(&self) {
+        let _ = self
+            .state
+            .subscribe()
+            .wait_for(|state| *state >= State::Cutting)
+            .await;
+    }
+
+    /// Message to kick logins with while draining, listeners stop accepting
+    /// entirely when there isn't one.
+    pub fn kick_message(&self, config: &Config) -> Option<String> {
+        // the new router is accepting for us, nothing new should be arriving here
+        if !self.is_stopping() || self.is_handed_off() {
+            return None;
+        }
+
+        config.shutdown.kick_message.clone()
+    }
+
+    /// Wait for proxied sessions to end, up to `deadline` unless the listeners
+    /// were handed off.
+    ///
+    /// Returns how many sessions finished on their own and how many were still
+    /// running when the router gave up on them.
+    pub async fn drain(&self, deadline: Duration) -> (usize, usize) {
+        let deadline = if self.is_handed_off() {
+            None
+        } else {
+            Some(deadline)
+        };
+        let ended_before = self.ended_sessions.load(Ordering::SeqCst);
+
+        let active = self.active_sessions.load(Ordering::SeqCst);
+        match deadline {
+            Some(deadline) => info!(
+                "Waiting up to {}s for {} proxied sessions to end",
+                deadline.as_secs(),
+                active
+            ),
+            None => info!("Waiting for {} proxied sessions to end", active),
+        }
+
+        let all_ended = async {
+            loop {
+                let ended = self.session_ended.notified();
+                if self.active_sessions.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                ended.await;
+            }
+        };
+
+        let deadline = async {
+            match deadline {
+                Some(deadline) => time::sleep(deadline).await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = all_ended => {}
+            _ = deadline => {}
+            _ = self.cut() => {}
+        }
+
+        let drained = self.ended_sessions.load(Ordering::SeqCst) - ended_before;
+        let cut = self.active_sessions.load(Ordering::SeqCst);
+        (drained, cut)
+    }
+}
+
+/// Held for as long as a client is being proxied.
+#[derive(Debug)]
+pub struct Session(Arc<Inner>);
+
+pub fn track_session(router: &Arc<Inner>) -> Session {
+    router
+        .shutdown
+        .active_sessions
+        .fetch_add(1, Ordering::SeqCst);
+    Session(router.clone())
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let shutdown = &self.0.shutdown;
+        shutdown.active_sessions.fetch_sub(1, Ordering::SeqCst);
+        shutdown.ended_sessions.fetch_add(1, Ordering::SeqCst);
+        shutdown.session_ended.notify_waiters();
+    }
+}